@@ -0,0 +1,242 @@
+//! Battery monitoring and battery life estimation.
+
+use std::collections::VecDeque;
+use std::str::FromStr;
+
+
+/// The battery level of a device, ordered from good to bad.
+#[derive(Debug, PartialEq, PartialOrd, Copy, Clone)]
+pub enum BatteryLevel {
+    Ok,
+    Low,
+    Critical,
+}
+
+impl Default for BatteryLevel {
+    fn default() -> Self {
+        BatteryLevel::Ok
+    }
+}
+
+impl BatteryLevel {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            BatteryLevel::Ok => "ok",
+            BatteryLevel::Low => "low",
+            BatteryLevel::Critical => "critical",
+        }
+    }
+}
+
+impl FromStr for BatteryLevel {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        match val {
+            "ok" => Ok(BatteryLevel::Ok),
+            "low" => Ok(BatteryLevel::Low),
+            "critical" => Ok(BatteryLevel::Critical),
+            _ => Err(format!("Invalid battery level: {}", val)),
+        }
+    }
+}
+
+/// Voltage thresholds of a device.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct BatteryThresholds {
+    pub low: f32,
+    pub critical: f32,
+    /// A level is only left again when the voltage rises this much above
+    /// its threshold, so that a voltage jittering around a threshold does
+    /// not cause repeated alerts.
+    pub hysteresis: f32,
+}
+
+impl Default for BatteryThresholds {
+    fn default() -> Self {
+        BatteryThresholds {
+            low: 3.3,
+            critical: 3.1,
+            hysteresis: 0.1,
+        }
+    }
+}
+
+impl BatteryThresholds {
+    /// Return the new battery level for the measured voltage.
+    pub fn level(&self, prev: BatteryLevel, voltage: f32) -> BatteryLevel {
+        let low = if prev >= BatteryLevel::Low { self.low + self.hysteresis } else { self.low };
+        let critical = if prev >= BatteryLevel::Critical { self.critical + self.hysteresis } else { self.critical };
+        if voltage < critical {
+            BatteryLevel::Critical
+        } else if voltage < low {
+            BatteryLevel::Low
+        } else {
+            BatteryLevel::Ok
+        }
+    }
+}
+
+/// Parse thresholds in the format `<low>/<critical>`, e.g. `3.3/3.1`.
+///
+/// The hysteresis is set to the default.
+impl FromStr for BatteryThresholds {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let mut parts = val.splitn(2, '/');
+        let low: Option<f32> = parts.next().and_then(|v| v.trim().parse().ok());
+        let critical: Option<f32> = parts.next().and_then(|v| v.trim().parse().ok());
+        match (low, critical) {
+            (Some(low), Some(critical)) if critical <= low => Ok(BatteryThresholds {
+                low: low,
+                critical: critical,
+                ..Default::default()
+            }),
+            _ => Err(format!("Invalid battery thresholds (expected \"<low>/<critical>\"): {}", val)),
+        }
+    }
+}
+
+/// Minimum number of seconds between two samples in the voltage history.
+static SAMPLE_INTERVAL: u64 = 3600;
+
+/// Samples older than this are dropped from the voltage history.
+static MAX_SAMPLE_AGE: u64 = 180 * 86400;
+
+/// Only the samples of this many seconds before the last sample are used for
+/// the estimation, since the discharge curve gets steeper towards the end.
+static ESTIMATION_WINDOW: u64 = 30 * 86400;
+
+/// The samples used for the estimation must span at least this many seconds.
+static MIN_ESTIMATION_SPAN: u64 = 3 * 86400;
+
+/// A voltage increase of this many volts is treated as a battery swap.
+static SWAP_VOLTAGE_INCREASE: f32 = 0.2;
+
+/// The voltage history of a device since its last battery swap.
+#[derive(Debug, Default, Clone)]
+pub struct VoltageHistory {
+    samples: VecDeque<(u64, f32)>,
+    /// Whether the battery life warning was sent for the current battery.
+    pub warned: bool,
+}
+
+impl VoltageHistory {
+    /// Add a voltage sample.
+    ///
+    /// Samples that arrive less than an hour after the previous sample are
+    /// ignored. Return whether the sample was added.
+    pub fn add(&mut self, timestamp: u64, voltage: f32) -> bool {
+        if let Some(&(last_timestamp, last_voltage)) = self.samples.back() {
+            if voltage - last_voltage >= SWAP_VOLTAGE_INCREASE {
+                info!("Battery voltage increased from {}V to {}V, assuming battery swap", last_voltage, voltage);
+                self.samples.clear();
+                self.warned = false;
+            } else if timestamp.saturating_sub(last_timestamp) < SAMPLE_INTERVAL {
+                return false;
+            }
+        }
+        self.samples.push_back((timestamp, voltage));
+        while self.samples.front().map_or(false, |&(t, _)| timestamp.saturating_sub(t) > MAX_SAMPLE_AGE) {
+            self.samples.pop_front();
+        }
+        true
+    }
+
+    pub fn samples<'a>(&'a self) -> ::std::collections::vec_deque::Iter<'a, (u64, f32)> {
+        self.samples.iter()
+    }
+
+    /// Estimate the number of seconds (from `now`) until the voltage reaches
+    /// the cut-off voltage.
+    ///
+    /// The estimation fits a straight line through the samples of the last
+    /// 30 days. Return `None` if there are not enough samples or the voltage
+    /// is not dropping.
+    pub fn estimate(&self, cutoff: f32, now: u64) -> Option<u64> {
+        let last = self.samples.back()?.0;
+        let window: Vec<(f64, f64)> = self.samples.iter()
+            .filter(|&&(t, _)| last - t <= ESTIMATION_WINDOW)
+            .map(|&(t, v)| (t as f64 - last as f64, v as f64))
+            .collect();
+        if window.len() < 3 || -window[0].0 < MIN_ESTIMATION_SPAN as f64 {
+            return None;
+        }
+
+        // Least squares fit
+        let n = window.len() as f64;
+        let mean_t = window.iter().map(|&(t, _)| t).sum::<f64>() / n;
+        let mean_v = window.iter().map(|&(_, v)| v).sum::<f64>() / n;
+        let cov: f64 = window.iter().map(|&(t, v)| (t - mean_t) * (v - mean_v)).sum();
+        let var: f64 = window.iter().map(|&(t, _)| (t - mean_t) * (t - mean_t)).sum();
+        let slope = cov / var;
+        if !(slope < 0.0) {
+            return None;
+        }
+
+        // Time of the cut-off relative to the last sample
+        let cutoff_t = mean_t + (cutoff as f64 - mean_v) / slope;
+        let cutoff_timestamp = last as f64 + cutoff_t;
+        if cutoff_timestamp <= now as f64 {
+            Some(0)
+        } else {
+            Some((cutoff_timestamp - now as f64) as u64)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("3.4/3.2".parse::<BatteryThresholds>().map(|t| (t.low, t.critical)), Ok((3.4, 3.2)));
+        assert!("3.2/3.4".parse::<BatteryThresholds>().is_err());
+        assert!("3.4".parse::<BatteryThresholds>().is_err());
+        assert_eq!(BatteryLevel::Critical.name().parse(), Ok(BatteryLevel::Critical));
+        assert!("empty".parse::<BatteryLevel>().is_err());
+    }
+
+    #[test]
+    fn test_level() {
+        let thresholds = BatteryThresholds::default();
+        assert_eq!(thresholds.level(BatteryLevel::Ok, 3.6), BatteryLevel::Ok);
+        assert_eq!(thresholds.level(BatteryLevel::Ok, 3.25), BatteryLevel::Low);
+        assert_eq!(thresholds.level(BatteryLevel::Ok, 3.0), BatteryLevel::Critical);
+
+        // Hysteresis
+        assert_eq!(thresholds.level(BatteryLevel::Low, 3.35), BatteryLevel::Low);
+        assert_eq!(thresholds.level(BatteryLevel::Low, 3.45), BatteryLevel::Ok);
+        assert_eq!(thresholds.level(BatteryLevel::Critical, 3.15), BatteryLevel::Critical);
+        assert_eq!(thresholds.level(BatteryLevel::Critical, 3.25), BatteryLevel::Low);
+
+        // Battery swap
+        assert_eq!(thresholds.level(BatteryLevel::Critical, 3.6), BatteryLevel::Ok);
+    }
+
+    #[test]
+    fn test_estimate() {
+        let day = 86400;
+        let mut history = VoltageHistory::default();
+        assert!(history.add(0, 3.60));
+        assert!(!history.add(600, 3.60));
+        assert_eq!(history.estimate(3.0, day), None);
+
+        // Linear discharge by 0.01V per day
+        for i in 1..11 {
+            assert!(history.add(i * day, 3.60 - 0.01 * i as f32));
+        }
+        let estimate = history.estimate(3.0, 10 * day).unwrap();
+        assert!(estimate > 49 * day && estimate < 51 * day, "{}", estimate);
+
+        // Battery swap
+        history.warned = true;
+        assert!(history.add(11 * day, 3.75));
+        assert_eq!(history.samples().count(), 1);
+        assert!(!history.warned);
+        assert_eq!(history.estimate(3.0, 11 * day), None);
+    }
+}
@@ -0,0 +1,244 @@
+//! Decoding of messages that the Threema Gateway POSTs to the callback URL.
+//!
+//! See https://gateway.threema.ch/en/developer/api for the format of the
+//! callback request and the end-to-end encrypted message types.
+
+use std::collections::{HashMap, VecDeque};
+
+use data_encoding::HEXLOWER_PERMISSIVE;
+use sodiumoxide::crypto::auth::hmacsha256;
+use sodiumoxide::crypto::box_;
+use sodiumoxide::utils::memcmp;
+
+use receipts::DeliveryStatus;
+
+
+/// The fields of a callback request.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CallbackRequest {
+    pub from: String,
+    pub to: String,
+    pub message_id: String,
+    pub date: u64,
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+    pub nickname: Option<String>,
+}
+
+/// Number of message IDs that are remembered to detect retried or replayed
+/// callback requests.
+static RECENT_MESSAGES: usize = 1000;
+
+/// The IDs of the most recently processed incoming messages.
+#[derive(Debug, Default)]
+pub struct RecentMessages {
+    ids: VecDeque<String>,
+}
+
+impl RecentMessages {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Remember the message ID and return whether it was not processed
+    /// before.
+    pub fn insert(&mut self, message_id: &str) -> bool {
+        let id = message_id.to_lowercase();
+        if self.ids.contains(&id) {
+            return false;
+        }
+        self.ids.push_back(id);
+        while self.ids.len() > RECENT_MESSAGES {
+            self.ids.pop_front();
+        }
+        true
+    }
+}
+
+/// A decrypted incoming message.
+#[derive(Debug, PartialEq, Clone)]
+pub enum IncomingMessage {
+    Text(String),
+    DeliveryReceipt {
+        status: DeliveryStatus,
+        message_ids: Vec<String>,
+    },
+    Other(u8),
+}
+
+/// Decode a `application/x-www-form-urlencoded` request body.
+fn parse_form(body: &str) -> HashMap<String, String> {
+    body.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            let key = url_decode(parts.next().unwrap_or(""));
+            let val = url_decode(parts.next().unwrap_or(""));
+            (key, val)
+        })
+        .collect()
+}
+
+fn url_decode(val: &str) -> String {
+    let bytes = val.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match HEXLOWER_PERMISSIVE.decode(&bytes[i + 1..i + 3]) {
+                    Ok(byte) => {
+                        decoded.extend(byte);
+                        i += 2;
+                    },
+                    Err(_) => decoded.push(b'%'),
+                }
+            },
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Parse the callback request body and verify its MAC.
+pub fn parse_request(body: &str, secret: &str) -> Result<CallbackRequest, String> {
+    let form = parse_form(body);
+    let field = |name: &str| form.get(name)
+        .map(|val| val.as_str())
+        .ok_or_else(|| format!("Callback request does not contain \"{}\" field", name));
+    let hex_field = |name: &str| field(name).and_then(|val| HEXLOWER_PERMISSIVE.decode(val.as_bytes())
+        .map_err(|e| format!("The \"{}\" field is not valid hex: {}", name, e)));
+
+    // The MAC covers the fields in this exact order
+    let mut state = hmacsha256::State::init(secret.as_bytes());
+    for name in &["from", "to", "messageId", "date", "nonce", "box"] {
+        state.update(field(name)?.as_bytes());
+    }
+    let expected_mac = state.finalize();
+    if !memcmp(&expected_mac.0, &hex_field("mac")?) {
+        return Err("Callback request has an invalid MAC".into());
+    }
+
+    Ok(CallbackRequest {
+        from: field("from")?.to_owned(),
+        to: field("to")?.to_owned(),
+        message_id: field("messageId")?.to_owned(),
+        date: field("date")?.parse().map_err(|_| "The \"date\" field does not contain a number".to_string())?,
+        nonce: hex_field("nonce")?,
+        ciphertext: hex_field("box")?,
+        nickname: form.get("nickname").cloned(),
+    })
+}
+
+/// Decrypt the message box with the sender's public key and our private key
+/// (both hex encoded).
+pub fn decrypt(req: &CallbackRequest, sender_public_key: &str, private_key: &str) -> Result<IncomingMessage, String> {
+    let public_key = HEXLOWER_PERMISSIVE.decode(sender_public_key.as_bytes()).ok()
+        .and_then(|bytes| box_::PublicKey::from_slice(&bytes))
+        .ok_or_else(|| format!("Invalid public key for {}", req.from))?;
+    let private_key = HEXLOWER_PERMISSIVE.decode(private_key.as_bytes()).ok()
+        .and_then(|bytes| box_::SecretKey::from_slice(&bytes))
+        .ok_or_else(|| "Invalid private key".to_string())?;
+    let nonce = box_::Nonce::from_slice(&req.nonce)
+        .ok_or_else(|| "Invalid nonce".to_string())?;
+    let plaintext = box_::open(&req.ciphertext, &nonce, &public_key, &private_key)
+        .map_err(|_| format!("Could not decrypt message {} from {}", req.message_id, req.from))?;
+    decode_message(&plaintext)
+}
+
+/// Decode a decrypted message: a type byte, the message body and PKCS#7 style
+/// padding.
+fn decode_message(data: &[u8]) -> Result<IncomingMessage, String> {
+    let padding = *data.last().ok_or_else(|| "Message is empty".to_string())? as usize;
+    if padding == 0 || padding + 1 > data.len() {
+        return Err("Message has invalid padding".into());
+    }
+    let body = &data[1..data.len() - padding];
+    match data[0] {
+        0x01 => String::from_utf8(body.to_vec())
+            .map(IncomingMessage::Text)
+            .map_err(|_| "Text message is not valid UTF-8".to_string()),
+        0x80 => {
+            let status = body.first().and_then(|val| DeliveryStatus::from_receipt(*val))
+                .ok_or_else(|| "Delivery receipt has an invalid status".to_string())?;
+            let message_ids = body[1..].chunks(8)
+                .filter(|chunk| chunk.len() == 8)
+                .map(|chunk| HEXLOWER_PERMISSIVE.encode(chunk))
+                .collect();
+            Ok(IncomingMessage::DeliveryReceipt { status, message_ids })
+        },
+        t => Ok(IncomingMessage::Other(t)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recent_messages() {
+        let mut recent = RecentMessages::new();
+        assert!(recent.insert("0102030405060708"));
+        assert!(recent.insert("0102030405060709"));
+        assert!(!recent.insert("0102030405060708"));
+        for i in 0..RECENT_MESSAGES {
+            recent.insert(&i.to_string());
+        }
+        assert!(recent.insert("0102030405060708"));
+    }
+
+    fn sign(fields: &[(&str, &str)], secret: &str) -> String {
+        let mut state = hmacsha256::State::init(secret.as_bytes());
+        for &(_, val) in fields {
+            state.update(val.as_bytes());
+        }
+        let mac = HEXLOWER_PERMISSIVE.encode(&state.finalize().0);
+        let mut body: Vec<String> = fields.iter().map(|&(key, val)| format!("{}={}", key, val)).collect();
+        body.push(format!("mac={}", mac));
+        body.join("&")
+    }
+
+    #[test]
+    fn test_url_decode() {
+        assert_eq!(url_decode("Hello+World%21"), "Hello World!");
+        assert_eq!(url_decode("100%"), "100%");
+        assert_eq!(url_decode("%4"), "%4");
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let fields = [
+            ("from", "ECHOECHO"),
+            ("to", "*SMARTML"),
+            ("messageId", "0123456789abcdef"),
+            ("date", "1514764800"),
+            ("nonce", "00"),
+            ("box", "ff"),
+        ];
+        let body = sign(&fields, "secret");
+        let req = parse_request(&body, "secret").unwrap();
+        assert_eq!(req.from, "ECHOECHO");
+        assert_eq!(req.date, 1514764800);
+        assert_eq!(req.ciphertext, vec![0xff]);
+        assert!(parse_request(&body, "wrong").is_err());
+    }
+
+    #[test]
+    fn test_decode_message() {
+        assert_eq!(
+            decode_message(&[0x01, b'h', b'i', 0x02, 0x02]).unwrap(),
+            IncomingMessage::Text("hi".into())
+        );
+        assert_eq!(
+            decode_message(&[0x80, 0x02, 1, 2, 3, 4, 5, 6, 7, 8, 0x01]).unwrap(),
+            IncomingMessage::DeliveryReceipt {
+                status: DeliveryStatus::Read,
+                message_ids: vec!["0102030405060708".into()],
+            }
+        );
+        assert!(decode_message(&[0x01, 0x05]).is_err());
+    }
+}
@@ -0,0 +1,198 @@
+//! Parsing of chat commands sent by recipients over Threema.
+
+use data_encoding::HEXLOWER_PERMISSIVE;
+
+use downlink::{AxSenseCommand, Downlink};
+
+/// A command sent by an authorised recipient.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Command {
+    /// Reply with the current state of all devices.
+    Status,
+    /// Pause notifications for the specified number of seconds, or until
+    /// `unmute` if no duration was given.
+    Mute(Option<u64>),
+    /// Resume notifications.
+    Unmute,
+    /// Use the current distance as the empty distance, either for the
+    /// specified device or for all devices.
+    Calibrate(Option<String>),
+    /// Claim to have picked up the mail after the mailbox was emptied.
+    Took,
+    /// Reconfigure the specified device, or all devices, with the next
+    /// downlink.
+    Set(AxSenseCommand, Option<String>),
+    /// Send a (confirmed) downlink to the specified device, or to all
+    /// devices, after the next uplink.
+    Downlink(Downlink, Option<String>),
+    /// Reply with the delivery statistics of the specified device, or of all
+    /// devices.
+    Stats(Option<String>),
+    Help,
+}
+
+/// The reply to messages that could not be parsed.
+pub static HELP: &'static str = "Available commands:\n\
+    - *status*: Show the state of all mailboxes\n\
+    - *mute [duration]*: Pause notifications (e.g. `mute 2h`)\n\
+    - *unmute*: Resume notifications\n\
+    - *calibrate [deveui]*: Use the current distance as the empty distance\n\
+    - *took*: Let the others know that you picked up the mail\n\
+    - *set <interval|keepalive|threshold> <value> [deveui]*: Reconfigure the sensor (e.g. `set interval 5m`, `set threshold 20cm`)\n\
+    - *downlink <port> <hex payload> [deveui]*: Send a downlink with the next uplink (e.g. `downlink 100 020168`)\n\
+    - *stats [deveui]*: Show the delivery statistics of the last 30 days";
+
+/// Phrases that claim the last "emptied" event.
+static TOOK_PHRASES: &'static [&'static str] = &["took", "took it", "i took it", "mine", "me"];
+
+/// Parse a duration like `90s`, `30m`, `2h` or `1d`. A number without unit
+/// is interpreted as hours.
+fn parse_duration(val: &str) -> Option<u64> {
+    let (number, factor) = match val.chars().last()? {
+        's' => (&val[..val.len() - 1], 1),
+        'm' => (&val[..val.len() - 1], 60),
+        'h' => (&val[..val.len() - 1], 3600),
+        'd' => (&val[..val.len() - 1], 24 * 3600),
+        _ => (val, 3600),
+    };
+    number.parse::<u64>().ok().and_then(|n| n.checked_mul(factor))
+}
+
+/// Parse a distance like `20cm` or `200mm` into millimeters. A number without
+/// unit is interpreted as millimeters.
+fn parse_distance(val: &str) -> Option<u16> {
+    let (number, factor) = if val.ends_with("cm") {
+        (&val[..val.len() - 2], 10)
+    } else if val.ends_with("mm") {
+        (&val[..val.len() - 2], 1)
+    } else {
+        (val, 1)
+    };
+    number.parse::<u16>().ok().and_then(|n| n.checked_mul(factor))
+}
+
+/// Parse the setting and value of a `set` command.
+///
+/// Unlike for `mute`, the intervals require a unit, since the device would
+/// otherwise be reconfigured by a factor of 60 or 3600 by mistake.
+fn parse_setting(setting: &str, val: &str) -> Result<AxSenseCommand, String> {
+    let val = val.to_lowercase();
+    let duration = || if val.ends_with(|c: char| c.is_ascii_digit()) {
+        Err(format!("Missing unit (s, m, h or d): {}", val))
+    } else {
+        parse_duration(&val).ok_or_else(|| format!("Invalid duration: {}", val))
+    };
+    match setting.to_lowercase().as_str() {
+        "interval" => duration().map(AxSenseCommand::MeasurementInterval),
+        "keepalive" => duration().map(AxSenseCommand::KeepaliveInterval),
+        "threshold" => parse_distance(&val)
+            .map(AxSenseCommand::DistanceThreshold)
+            .ok_or_else(|| format!("Invalid distance: {}", val)),
+        _ => Err(format!("Unknown setting: {}", setting)),
+    }
+}
+
+/// Parse the port and the hex encoded payload of a `downlink` command.
+fn parse_downlink(port: &str, payload: &str) -> Result<Downlink, String> {
+    // Ports 224 and up are reserved by LoRaWAN
+    let port = match port.parse::<u8>() {
+        Ok(port) if port >= 1 && port <= 223 => port,
+        _ => return Err(format!("Invalid port: {}", port)),
+    };
+    let payload = HEXLOWER_PERMISSIVE.decode(payload.as_bytes())
+        .map_err(|_| format!("Invalid payload: {}", payload))?;
+    Ok(Downlink { port: port, payload: payload, confirmed: true })
+}
+
+pub fn parse(text: &str) -> Result<Command, String> {
+    // Ignore quoted lines, so that recipients can reply to a notification
+    let text = text.lines()
+        .filter(|line| !line.starts_with('>'))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let normalized = text.split_whitespace()
+        .map(|word| word.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase())
+        .collect::<Vec<_>>()
+        .join(" ");
+    if TOOK_PHRASES.contains(&normalized.as_str()) {
+        return Ok(Command::Took);
+    }
+
+    let mut words = text.split_whitespace();
+    let command = match words.next() {
+        Some(command) => command.to_lowercase(),
+        None => return Err("Empty command".into()),
+    };
+    let arg = words.next();
+    match (command.as_str(), arg) {
+        ("status", None) => Ok(Command::Status),
+        ("mute", None) => Ok(Command::Mute(None)),
+        ("mute", Some(duration)) => parse_duration(&duration.to_lowercase())
+            .map(|secs| Command::Mute(Some(secs)))
+            .ok_or_else(|| format!("Invalid duration: {}", duration)),
+        ("unmute", None) => Ok(Command::Unmute),
+        ("calibrate", deveui) => Ok(Command::Calibrate(deveui.map(|d| d.to_owned()))),
+        ("set", Some(setting)) => match words.next() {
+            Some(val) => parse_setting(setting, val)
+                .map(|cmd| Command::Set(cmd, words.next().map(|d| d.to_owned()))),
+            None => Err(format!("Missing value for {}", setting)),
+        },
+        ("downlink", Some(port)) => match words.next() {
+            Some(payload) => parse_downlink(port, payload)
+                .map(|downlink| Command::Downlink(downlink, words.next().map(|d| d.to_owned()))),
+            None => Err("Missing payload".into()),
+        },
+        ("stats", deveui) => Ok(Command::Stats(deveui.map(|d| d.to_owned()))),
+        ("help", None) => Ok(Command::Help),
+        _ => Err(format!("Unknown command: {}", text.trim())),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("Status"), Ok(Command::Status));
+        assert_eq!(parse(" mute 2h "), Ok(Command::Mute(Some(7200))));
+        assert_eq!(parse("mute 30m"), Ok(Command::Mute(Some(1800))));
+        assert!(parse("mute 18446744073709551615d").is_err());
+        assert_eq!(parse("mute 3"), Ok(Command::Mute(Some(10800))));
+        assert_eq!(parse("mute"), Ok(Command::Mute(None)));
+        assert_eq!(parse("unmute"), Ok(Command::Unmute));
+        assert_eq!(parse("calibrate"), Ok(Command::Calibrate(None)));
+        assert_eq!(
+            parse("calibrate 0004A30B001A2B3C"),
+            Ok(Command::Calibrate(Some("0004A30B001A2B3C".into())))
+        );
+        assert_eq!(parse("I took it!"), Ok(Command::Took));
+        assert_eq!(parse("> *SMARTML: Mailbox was emptied.\n\nMine"), Ok(Command::Took));
+        assert_eq!(parse("set interval 5m"), Ok(Command::Set(AxSenseCommand::MeasurementInterval(300), None)));
+        assert_eq!(
+            parse("set keepalive 12h 0004A30B001A2B3C"),
+            Ok(Command::Set(AxSenseCommand::KeepaliveInterval(43200), Some("0004A30B001A2B3C".into())))
+        );
+        assert_eq!(parse("Set threshold 20cm"), Ok(Command::Set(AxSenseCommand::DistanceThreshold(200), None)));
+        assert_eq!(parse("set threshold 150"), Ok(Command::Set(AxSenseCommand::DistanceThreshold(150), None)));
+        let downlink = Downlink { port: 100, payload: vec![0x02, 0x01, 0x68], confirmed: true };
+        assert_eq!(parse("downlink 100 020168"), Ok(Command::Downlink(downlink.clone(), None)));
+        assert_eq!(
+            parse("Downlink 100 020168 0004A30B001A2B3C"),
+            Ok(Command::Downlink(downlink, Some("0004A30B001A2B3C".into())))
+        );
+        assert_eq!(parse("stats"), Ok(Command::Stats(None)));
+        assert_eq!(parse("Stats 0004A30B001A2B3C"), Ok(Command::Stats(Some("0004A30B001A2B3C".into()))));
+        assert!(parse("set interval").is_err());
+        assert!(parse("set interval 5").is_err());
+        assert!(parse("set volume 11").is_err());
+        assert!(parse("downlink 100").is_err());
+        assert!(parse("downlink 0 020168").is_err());
+        assert!(parse("downlink 224 020168").is_err());
+        assert!(parse("downlink 100 02016").is_err());
+        assert!(parse("mute forever").is_err());
+        assert!(parse("hello").is_err());
+        assert!(parse("").is_err());
+    }
+}
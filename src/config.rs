@@ -1,4 +1,18 @@
+use std::collections::HashMap;
 use std::env;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use battery::BatteryThresholds;
+use downlink::AxSenseLayout;
+use influxdb::{InfluxApi, Precision};
+use radio::{CoverageThresholds, AIRTIME_BUDGET};
+use routing::{self, Backend, EventType, Group, Recipient, DEFAULT_EVENTS};
+use schedule::{QuietAction, Schedule};
+use statistics::DigestSchedule;
+use temperature::TemperatureLimits;
+use templates::{DistanceUnit, Language, Templates};
 
 
 #[derive(Debug)]
@@ -7,44 +21,315 @@ pub struct Config {
     pub ttn_access_key: String,
 
     pub threema_from: String,
-    pub threema_to: Vec<String>,
     pub threema_secret: String,
     pub threema_private_key: String,
+    pub threema_callback_listen: Option<String>,
+    pub metrics_listen: Option<String>,
+
+    /// Recipient groups and their subscriptions. Contains at least the
+    /// `default` group with the members of `THREEMA_TO`.
+    pub groups: Vec<Group>,
 
     pub influxdb: Option<InfluxConfig>,
+    pub state_mqtt: Option<StateMqttConfig>,
+
+    pub outbox_path: String,
+    pub outbox_max_age: u64,
+
+    /// Path of the file with the notifications held during quiet hours.
+    pub held_path: String,
+
+    pub event_log_path: String,
+    /// Seconds after which event log entries are pruned.
+    pub event_log_retention: u64,
+    pub digest_schedule: Option<DigestSchedule>,
+
+    pub calibration_path: String,
+    pub calibration_margin: u16,
+
+    pub language: Language,
+    pub recipient_languages: HashMap<String, Language>,
+    pub distance_unit: DistanceUnit,
+    pub device_names: HashMap<String, String>,
+    pub templates: Templates,
+
+    pub quiet_hours: Schedule,
+    pub quiet_action: QuietAction,
+    pub recipient_quiet_actions: HashMap<String, QuietAction>,
+
+    pub rate_limit_per_hour: usize,
+    pub dedup_window: u64,
+
+    pub battery_thresholds: BatteryThresholds,
+    pub device_battery_thresholds: HashMap<String, BatteryThresholds>,
+    pub battery_history_path: String,
+    pub battery_cutoff: f32,
+    /// Seconds before the projected battery cut-off to send a warning.
+    pub battery_warning_period: u64,
+
+    /// Seconds without uplink after which a device is considered offline.
+    pub offline_timeout: u64,
+
+    pub temperature_limits: TemperatureLimits,
+    pub device_temperature_limits: HashMap<String, TemperatureLimits>,
+
+    pub coverage_thresholds: CoverageThresholds,
+
+    /// Airtime (in nanoseconds) per 24 hours at which a warning is sent.
+    pub airtime_warning: u64,
+
+    /// Encoding of the `set` commands, or `None` if it is not configured.
+    pub axsense_layout: Option<AxSenseLayout>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct InfluxConfig {
-    pub user: String,
-    pub pass: String,
-    pub db: String,
     pub url: String,
+    pub api: InfluxApi,
+    pub precision: Precision,
+    /// File to which points are spooled while InfluxDB is unreachable.
+    pub buffer_path: String,
+}
+
+/// The local MQTT broker to which the device state is republished.
+#[derive(Debug, Clone)]
+pub struct StateMqttConfig {
+    /// Address of the broker, e.g. `localhost:1883`.
+    pub broker: String,
+    pub user: Option<String>,
+    pub pass: Option<String>,
+    /// Prefix of the state topics.
+    pub prefix: String,
+    /// Prefix of the Home Assistant discovery topics, or `None` if discovery
+    /// is disabled.
+    pub discovery_prefix: Option<String>,
 }
 
 fn get_env_var(name: &str) -> Result<String, String> {
     env::var(name).map_err(|_| format!("Missing {} env var", name))
 }
 
+fn parse_env_var<T>(name: &str, default: T) -> Result<T, String> where T: FromStr, T::Err: fmt::Display {
+    match env::var(name) {
+        Ok(val) => val.parse().map_err(|e| format!("Invalid value for {} env var: {}", name, e)),
+        Err(_) => Ok(default),
+    }
+}
+
+fn parse_optional_env_var<T>(name: &str) -> Result<Option<T>, String> where T: FromStr, T::Err: fmt::Display {
+    match env::var(name) {
+        Ok(val) => val.parse().map(Some).map_err(|e| format!("Invalid value for {} env var: {}", name, e)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Parse a comma separated list of `key:value` pairs.
+fn parse_env_map<T: FromStr>(name: &str) -> Result<HashMap<String, T>, String> {
+    let val = match env::var(name) {
+        Ok(val) => val,
+        Err(_) => return Ok(HashMap::new()),
+    };
+    val.split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            let mut parts = entry.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(val)) => val.trim().parse()
+                    .map(|val| (key.trim().to_owned(), val))
+                    .map_err(|_| format!("Invalid value in {} env var: {}", name, entry)),
+                _ => Err(format!("Invalid entry in {} env var (expected key:value): {}", name, entry)),
+            }
+        })
+        .collect()
+}
+
+/// Parse the recipient groups.
+///
+/// The recipients in `THREEMA_TO` form the `default` group. More groups are
+/// defined with `GROUP_<NAME>_MEMBERS` and `GROUP_<NAME>_EVENTS` env vars.
+fn parse_groups(threema_to: &str) -> Result<Vec<Group>, String> {
+    let default_events = DEFAULT_EVENTS.iter().map(|e| e.name()).collect::<Vec<_>>().join(",");
+    let events_for = |name: &str| env::var(format!("GROUP_{}_EVENTS", name))
+        .unwrap_or_else(|_| default_events.clone());
+
+    let mut groups = vec![Group::parse("default", threema_to, &events_for("DEFAULT"))?];
+    let mut names: Vec<String> = env::vars()
+        .filter_map(|(key, _)| {
+            if key.starts_with("GROUP_") && key.ends_with("_MEMBERS") && key.len() > 14 {
+                Some(key[6..key.len() - 8].to_owned())
+            } else {
+                None
+            }
+        })
+        .filter(|name| name != "DEFAULT")
+        .collect();
+    names.sort();
+    for name in names {
+        let members = get_env_var(&format!("GROUP_{}_MEMBERS", name))?;
+        let group = Group::parse(&name.to_lowercase(), &members, &events_for(&name))
+            .map_err(|e| format!("Invalid group {}: {}", name, e))?;
+        groups.push(group);
+    }
+    Ok(groups)
+}
+
 impl Config {
     pub fn init() -> Result<Config, String> {
-        let influx_user = env::var("INFLUXDB_USER").ok();
-        let influx_pass = env::var("INFLUXDB_PASS").ok();
-        let influx_db = env::var("INFLUXDB_DB").ok();
-        let influx_url = env::var("INFLUXDB_URL").ok();
-        let influxdb = match (influx_user, influx_pass, influx_db, influx_url) {
-            (Some(user), Some(pass), Some(db), Some(url)) => Some(InfluxConfig { user, pass, db, url }),
+        let influx_api = match parse_env_var("INFLUXDB_API_VERSION", 1)? {
+            1 => match (env::var("INFLUXDB_USER"), env::var("INFLUXDB_PASS"), env::var("INFLUXDB_DB")) {
+                (Ok(user), Ok(pass), Ok(db)) => Some(InfluxApi::V1 { user, pass, db }),
+                _ => None,
+            },
+            2 => Some(InfluxApi::V2 {
+                org: get_env_var("INFLUXDB_ORG")?,
+                bucket: get_env_var("INFLUXDB_BUCKET")?,
+                token: get_env_var("INFLUXDB_TOKEN")?,
+            }),
+            v => return Err(format!("Unsupported INFLUXDB_API_VERSION (expected 1 or 2): {}", v)),
+        };
+        let influxdb = match (env::var("INFLUXDB_URL"), influx_api) {
+            (Ok(url), Some(api)) => Some(InfluxConfig {
+                url: url,
+                api: api,
+                precision: parse_env_var("INFLUXDB_PRECISION", Precision::Nanoseconds)?,
+                buffer_path: env::var("INFLUXDB_BUFFER_PATH").unwrap_or_else(|_| "influxdb.buffer".into()),
+            }),
             _ => None,
         };
 
+        let threema_to = get_env_var("THREEMA_TO")?;
+
+        let defaults = BatteryThresholds::default();
+        let battery_hysteresis = parse_env_var("BATTERY_HYSTERESIS", defaults.hysteresis)?;
+        let battery_thresholds = BatteryThresholds {
+            low: parse_env_var("BATTERY_LOW_VOLTAGE", defaults.low)?,
+            critical: parse_env_var("BATTERY_CRITICAL_VOLTAGE", defaults.critical)?,
+            hysteresis: battery_hysteresis,
+        };
+        if battery_thresholds.critical > battery_thresholds.low {
+            return Err(format!("Invalid battery thresholds (BATTERY_CRITICAL_VOLTAGE must not exceed BATTERY_LOW_VOLTAGE): {}/{}",
+                               battery_thresholds.low, battery_thresholds.critical));
+        }
+        let coverage_defaults = CoverageThresholds::default();
+
+        let mut device_battery_thresholds: HashMap<String, BatteryThresholds> = parse_env_map("DEVICE_BATTERY_THRESHOLDS")?;
+        for thresholds in device_battery_thresholds.values_mut() {
+            thresholds.hysteresis = battery_hysteresis;
+        }
+
+        let airtime_percent = match parse_env_var::<u64>("AIRTIME_WARNING_PERCENT", 80)? {
+            percent @ 1..=100 => percent,
+            percent => return Err(format!("Invalid AIRTIME_WARNING_PERCENT (expected 1 to 100): {}", percent)),
+        };
+
         Ok(Config {
             ttn_app_id: get_env_var("TTN_APP_ID")?,
             ttn_access_key: get_env_var("TTN_ACCESS_KEY")?,
             threema_from: get_env_var("THREEMA_FROM")?,
-            threema_to: get_env_var("THREEMA_TO")?.split(',').map(|s| s.to_owned()).collect(),
             threema_secret: get_env_var("THREEMA_SECRET")?,
             threema_private_key: get_env_var("THREEMA_PRIVATE_KEY")?,
+            threema_callback_listen: env::var("THREEMA_CALLBACK_LISTEN").ok(),
+            metrics_listen: env::var("METRICS_LISTEN").ok(),
+            groups: parse_groups(&threema_to)?,
             influxdb: influxdb,
+            state_mqtt: match env::var("STATE_MQTT_BROKER") {
+                Ok(broker) => Some(StateMqttConfig {
+                    broker: broker,
+                    user: env::var("STATE_MQTT_USER").ok(),
+                    pass: env::var("STATE_MQTT_PASS").ok(),
+                    prefix: env::var("STATE_MQTT_PREFIX").unwrap_or_else(|_| "smartmail".into()),
+                    discovery_prefix: match env::var("HOMEASSISTANT_DISCOVERY_PREFIX") {
+                        Ok(ref prefix) if prefix.is_empty() => None,
+                        Ok(prefix) => Some(prefix),
+                        Err(_) => Some("homeassistant".into()),
+                    },
+                }),
+                Err(_) => None,
+            },
+            outbox_path: env::var("OUTBOX_PATH").unwrap_or_else(|_| "outbox.json".into()),
+            outbox_max_age: parse_env_var::<u64>("OUTBOX_MAX_AGE_HOURS", 24)? * 3600,
+            held_path: env::var("HELD_PATH").unwrap_or_else(|_| "held.json".into()),
+            event_log_path: env::var("EVENT_LOG_PATH").unwrap_or_else(|_| "events.jsonl".into()),
+            event_log_retention: parse_env_var::<u64>("EVENT_LOG_RETENTION_DAYS", 90)? * 24 * 3600,
+            digest_schedule: parse_optional_env_var("DIGEST_SCHEDULE")?,
+            calibration_path: env::var("CALIBRATION_PATH").unwrap_or_else(|_| "calibration.json".into()),
+            calibration_margin: parse_env_var("CALIBRATION_MARGIN_MM", 50)?,
+            language: parse_env_var("NOTIFICATION_LANGUAGE", Language::En)?,
+            recipient_languages: parse_env_map("RECIPIENT_LANGUAGES")?,
+            distance_unit: parse_env_var("DISTANCE_UNIT", DistanceUnit::Centimeters)?,
+            device_names: parse_env_map("DEVICE_NAMES")?,
+            templates: match env::var("TEMPLATE_DIR") {
+                Ok(dir) => Templates::load(Path::new(&dir))?,
+                Err(_) => Templates::default(),
+            },
+            quiet_hours: parse_env_var("QUIET_HOURS", Schedule::default())?,
+            quiet_action: parse_env_var("QUIET_HOURS_ACTION", QuietAction::Delay)?,
+            recipient_quiet_actions: parse_env_map("RECIPIENT_QUIET_HOURS_ACTIONS")?,
+            rate_limit_per_hour: parse_env_var("RATE_LIMIT_PER_HOUR", 6)?,
+            dedup_window: parse_env_var::<u64>("DEDUP_WINDOW_MINUTES", 10)? * 60,
+            battery_thresholds: battery_thresholds,
+            device_battery_thresholds: device_battery_thresholds,
+            battery_history_path: env::var("BATTERY_HISTORY_PATH").unwrap_or_else(|_| "battery.json".into()),
+            battery_cutoff: parse_env_var("BATTERY_CUTOFF_VOLTAGE", 3.0)?,
+            battery_warning_period: parse_env_var::<u64>("BATTERY_WARNING_WEEKS", 4)? * 7 * 86400,
+            offline_timeout: (parse_env_var::<f32>("KEEPALIVE_INTERVAL_HOURS", 24.0)?
+                              * parse_env_var::<f32>("OFFLINE_AFTER_INTERVALS", 2.5)? * 3600.0) as u64,
+            temperature_limits: TemperatureLimits {
+                min: parse_optional_env_var("TEMPERATURE_MIN")?,
+                max: parse_optional_env_var("TEMPERATURE_MAX")?,
+            },
+            device_temperature_limits: parse_env_map("DEVICE_TEMPERATURE_LIMITS")?,
+            coverage_thresholds: CoverageThresholds {
+                min_rssi: parse_env_var("POOR_COVERAGE_RSSI", coverage_defaults.min_rssi)?,
+                min_snr: parse_env_var("POOR_COVERAGE_SNR", coverage_defaults.min_snr)?,
+                uplinks: parse_env_var("POOR_COVERAGE_UPLINKS", coverage_defaults.uplinks)?,
+            },
+            airtime_warning: AIRTIME_BUDGET / 100 * airtime_percent,
+            axsense_layout: match parse_optional_env_var("AXSENSE_CONFIG_PORT")? {
+                Some(port) => Some(AxSenseLayout {
+                    port: port,
+                    registers: parse_env_map("AXSENSE_REGISTERS")?,
+                }),
+                None => None,
+            },
         })
     }
+
+    /// Return all recipients that subscribed to the event.
+    pub fn recipients_for(&self, event: EventType) -> Vec<Recipient> {
+        routing::recipients_for(&self.groups, event)
+    }
+
+    /// Return whether the Threema ID may send chat commands.
+    pub fn is_authorised(&self, threema_id: &str) -> bool {
+        self.groups.iter()
+            .flat_map(|g| g.members.iter())
+            .any(|m| m.backend == Backend::Threema && m.address == threema_id)
+    }
+
+    /// Return the language for notifications to the specified recipient.
+    pub fn language_for(&self, recipient: &str) -> Language {
+        self.recipient_languages.get(recipient).cloned().unwrap_or(self.language)
+    }
+
+    /// Return what happens to notifications for the specified recipient
+    /// during quiet hours.
+    pub fn quiet_action_for(&self, recipient: &str) -> QuietAction {
+        self.recipient_quiet_actions.get(recipient).cloned().unwrap_or(self.quiet_action)
+    }
+
+    /// Return the battery thresholds of a device.
+    pub fn battery_thresholds_for(&self, deveui: &str) -> BatteryThresholds {
+        self.device_battery_thresholds.get(deveui).cloned().unwrap_or(self.battery_thresholds)
+    }
+
+    /// Return the temperature limits of a device.
+    pub fn temperature_limits_for(&self, deveui: &str) -> TemperatureLimits {
+        self.device_temperature_limits.get(deveui).cloned().unwrap_or(self.temperature_limits)
+    }
+
+    /// Return the configured name of a device, or its DevEUI.
+    pub fn device_name(&self, deveui: &str) -> String {
+        self.device_names.get(deveui).cloned().unwrap_or_else(|| deveui.to_owned())
+    }
 }
@@ -0,0 +1,305 @@
+//! State of the ax-sense devices, indexed by their DevEUI.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::{self, Value};
+
+use battery::{BatteryLevel, VoltageHistory};
+use frames::FrameCounter;
+use radio::{Airtime, Coverage, DataRateHistory};
+use ttn::DataRate;
+use temperature::TemperatureState;
+
+
+/// If the distance falls below this value, the system assumes that the mailbox
+/// is non-empty. Used for devices that have not been calibrated.
+pub static DEFAULT_THRESHOLD: u16 = 300;
+
+/// An OTAA join of a device.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Join {
+    pub dev_addr: String,
+    pub data_rate: Option<DataRate>,
+    /// Unix timestamp of the join.
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct DeviceState {
+    pub last_distance: Option<u16>,
+    pub last_voltage: Option<f32>,
+    pub last_temperature: Option<f32>,
+    /// Unix timestamp of the last uplink.
+    pub last_seen: Option<u64>,
+    /// Distance measured in the empty mailbox, set by calibration.
+    pub empty_distance: Option<u16>,
+    pub battery_level: BatteryLevel,
+    pub voltage_history: VoltageHistory,
+    /// Whether the offline alert was sent.
+    pub offline: bool,
+    pub temperature_state: TemperatureState,
+    pub frames: FrameCounter,
+    /// Number of uplinks that were lost, received twice, and number of frame
+    /// counter resets since startup.
+    pub lost_frames: u64,
+    pub duplicate_frames: u64,
+    pub counter_resets: u64,
+    pub last_join: Option<Join>,
+    /// Number of joins since startup.
+    pub joins: u64,
+    /// Link quality of the last uplink.
+    pub last_rssi: Option<f32>,
+    pub last_snr: Option<f32>,
+    pub last_gateways: usize,
+    pub coverage: Coverage,
+    pub airtime: Airtime,
+    pub data_rates: DataRateHistory,
+}
+
+impl DeviceState {
+    /// Return the distance below which the mailbox is considered full.
+    ///
+    /// For calibrated devices this is `margin` millimeters below the empty
+    /// distance.
+    pub fn threshold(&self, margin: u16) -> u16 {
+        match self.empty_distance {
+            Some(dist) => dist.saturating_sub(margin),
+            None => DEFAULT_THRESHOLD,
+        }
+    }
+
+    /// Return whether the mailbox is full, or `None` if no distance has been
+    /// measured yet.
+    pub fn is_full(&self, margin: u16) -> Option<bool> {
+        self.last_distance.map(|dist| dist < self.threshold(margin))
+    }
+
+    /// Record an OTAA join. The frame counter starts again after a join.
+    pub fn joined(&mut self, dev_addr: &str, data_rate: Option<DataRate>, now: u64) {
+        self.last_join = Some(Join {
+            dev_addr: dev_addr.to_owned(),
+            data_rate: data_rate,
+            timestamp: now,
+        });
+        self.joins += 1;
+        self.frames.reset();
+    }
+
+    /// Record an uplink. Return whether the device was offline.
+    pub fn seen(&mut self, now: u64) -> bool {
+        let was_offline = self.offline;
+        self.last_seen = Some(now);
+        self.offline = false;
+        was_offline
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Devices {
+    devices: BTreeMap<String, DeviceState>,
+    calibration_path: Option<PathBuf>,
+    battery_path: Option<PathBuf>,
+}
+
+/// Read a JSON file, or return `None` if it does not exist.
+fn read_json(path: &Path) -> Result<Option<Value>, String> {
+    let mut contents = String::new();
+    match File::open(path) {
+        Ok(mut file) => {
+            file.read_to_string(&mut contents)
+                .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        },
+        Err(_) => return Ok(None),
+    };
+    serde_json::from_str(&contents)
+        .map(Some)
+        .map_err(|e| format!("Could not parse {}: {}", path.display(), e))
+}
+
+/// Atomically replace a JSON file.
+fn write_json(path: &Path, value: &Value) {
+    let tmp_path = path.with_extension("tmp");
+    let res = File::create(&tmp_path)
+        .and_then(|mut file| file.write_all(value.to_string().as_bytes()))
+        .and_then(|_| fs::rename(&tmp_path, path));
+    if let Err(e) = res {
+        error!("Could not write {}: {}", path.display(), e);
+    }
+}
+
+impl Devices {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Return the state of the specified device, creating it if necessary.
+    pub fn get_mut(&mut self, deveui: &str) -> &mut DeviceState {
+        self.devices.entry(deveui.to_owned()).or_insert_with(Default::default)
+    }
+
+    pub fn get(&self, deveui: &str) -> Option<&DeviceState> {
+        self.devices.get(deveui)
+    }
+
+    pub fn iter<'a>(&'a self) -> ::std::collections::btree_map::Iter<'a, String, DeviceState> {
+        self.devices.iter()
+    }
+
+    /// Mark all devices as offline that did not send an uplink for `timeout`
+    /// seconds, and return the DevEUIs of the devices that went offline.
+    ///
+    /// Devices that did not send an uplink since `started` (e.g. devices that
+    /// are only known from the calibration file) are measured from then.
+    pub fn check_offline(&mut self, now: u64, timeout: u64, started: u64) -> Vec<String> {
+        let mut offline = vec![];
+        for (deveui, state) in self.devices.iter_mut() {
+            let last_seen = state.last_seen.unwrap_or(started);
+            if !state.offline && now.saturating_sub(last_seen) > timeout {
+                state.offline = true;
+                offline.push(deveui.clone());
+            }
+        }
+        offline
+    }
+
+    /// Load the calibrated empty distances from the specified file.
+    ///
+    /// The path is remembered for `calibrate`. A missing file is not an
+    /// error.
+    pub fn load_calibration(&mut self, path: &Path) -> Result<(), String> {
+        self.calibration_path = Some(path.to_owned());
+        let decoded = match read_json(path)? {
+            Some(decoded) => decoded,
+            None => return Ok(()),
+        };
+        let entries = decoded.as_object()
+            .ok_or_else(|| format!("Calibration file {} does not contain an object", path.display()))?;
+        for (deveui, val) in entries {
+            match val.as_u64() {
+                Some(dist) => self.get_mut(deveui).empty_distance = Some(dist as u16),
+                None => warn!("Ignoring invalid calibration for {}: {}", deveui, val),
+            }
+        }
+        Ok(())
+    }
+
+    /// Use the last measured distance as the empty distance of the device.
+    ///
+    /// Return the new empty distance, or `None` if no distance has been
+    /// measured yet.
+    pub fn calibrate(&mut self, deveui: &str) -> Option<u16> {
+        let dist = {
+            let state = self.devices.get_mut(deveui)?;
+            let dist = state.last_distance?;
+            state.empty_distance = Some(dist);
+            dist
+        };
+        self.save_calibration();
+        Some(dist)
+    }
+
+    fn save_calibration(&self) {
+        let path = match self.calibration_path {
+            Some(ref path) => path,
+            None => return,
+        };
+        let json: serde_json::Map<String, Value> = self.devices.iter()
+            .filter_map(|(deveui, state)| state.empty_distance.map(|dist| (deveui.clone(), dist.into())))
+            .collect();
+        write_json(path, &Value::Object(json));
+    }
+
+    /// Load the battery voltage histories and levels from the specified
+    /// file.
+    ///
+    /// The path is remembered for `add_voltage`. A missing file is not an
+    /// error.
+    pub fn load_battery_history(&mut self, path: &Path) -> Result<(), String> {
+        self.battery_path = Some(path.to_owned());
+        let decoded = match read_json(path)? {
+            Some(decoded) => decoded,
+            None => return Ok(()),
+        };
+        let entries = decoded.as_object()
+            .ok_or_else(|| format!("Battery history file {} does not contain an object", path.display()))?;
+        for (deveui, val) in entries {
+            let state = self.get_mut(deveui);
+            let samples = val.get("samples").and_then(|s| s.as_array()).map(|s| s.as_slice()).unwrap_or(&[]);
+            for sample in samples {
+                match (sample.get(0).and_then(|t| t.as_u64()), sample.get(1).and_then(|v| v.as_f64())) {
+                    (Some(timestamp), Some(voltage)) => { state.voltage_history.add(timestamp, voltage as f32); },
+                    _ => warn!("Ignoring invalid battery sample for {}: {}", deveui, sample),
+                }
+            }
+            state.voltage_history.warned = val.get("warned").and_then(|w| w.as_bool()).unwrap_or(false);
+            state.battery_level = val.get("level").and_then(|l| l.as_str()).and_then(|l| l.parse().ok())
+                .unwrap_or_default();
+        }
+        Ok(())
+    }
+
+    /// Add a battery voltage sample to the history of the device.
+    pub fn add_voltage(&mut self, deveui: &str, timestamp: u64, voltage: f32) {
+        if self.get_mut(deveui).voltage_history.add(timestamp, voltage) {
+            self.save_battery_history();
+        }
+    }
+
+    pub fn save_battery_history(&self) {
+        let path = match self.battery_path {
+            Some(ref path) => path,
+            None => return,
+        };
+        let json: serde_json::Map<String, Value> = self.devices.iter()
+            .filter(|&(_, state)| state.voltage_history.samples().next().is_some() || state.battery_level != BatteryLevel::Ok)
+            .map(|(deveui, state)| {
+                let samples: Vec<Value> = state.voltage_history.samples()
+                    .map(|&(timestamp, voltage)| json!([timestamp, voltage]))
+                    .collect();
+                (deveui.clone(), json!({
+                    "samples": samples,
+                    "warned": state.voltage_history.warned,
+                    "level": state.battery_level.name(),
+                }))
+            })
+            .collect();
+        write_json(path, &Value::Object(json));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_calibrate() {
+        let mut devices = Devices::new();
+        assert_eq!(devices.calibrate("0004a30b001a2b3c"), None);
+
+        devices.get_mut("0004a30b001a2b3c").last_distance = Some(280);
+        assert_eq!(devices.get("0004a30b001a2b3c").unwrap().is_full(50), Some(true));
+
+        assert_eq!(devices.calibrate("0004a30b001a2b3c"), Some(280));
+        let state = devices.get("0004a30b001a2b3c").unwrap();
+        assert_eq!(state.threshold(50), 230);
+        assert_eq!(state.is_full(50), Some(false));
+    }
+
+    #[test]
+    fn test_offline() {
+        let mut devices = Devices::new();
+        devices.get_mut("0004a30b001a2b3c").seen(1000);
+        devices.get_mut("0004a30b001a2b3d");
+        assert_eq!(devices.check_offline(1500, 600, 1200), Vec::<String>::new());
+        assert_eq!(devices.check_offline(1601, 600, 1200), vec!["0004a30b001a2b3c".to_owned()]);
+        assert_eq!(devices.check_offline(1801, 600, 1200), vec!["0004a30b001a2b3d".to_owned()]);
+        assert_eq!(devices.check_offline(3000, 600, 1200), Vec::<String>::new());
+
+        assert!(devices.get_mut("0004a30b001a2b3c").seen(3000));
+        assert!(!devices.get_mut("0004a30b001a2b3c").seen(3100));
+    }
+}
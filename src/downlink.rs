@@ -0,0 +1,184 @@
+//! Downlinks to the devices.
+//!
+//! Class A devices can only receive a downlink right after an uplink, so
+//! downlinks are queued per device and published one at a time when the
+//! device sends an uplink.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use data_encoding::{BASE64, HEXUPPER};
+use serde_json::Value;
+
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Downlink {
+    pub port: u8,
+    pub payload: Vec<u8>,
+    /// Whether the device must acknowledge the downlink.
+    pub confirmed: bool,
+}
+
+impl Downlink {
+    /// Return the JSON message that schedules the downlink.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "port": self.port,
+            "confirmed": self.confirmed,
+            "payload_raw": BASE64.encode(&self.payload),
+        })
+    }
+}
+
+impl fmt::Display for Downlink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "downlink {} on port {}", HEXUPPER.encode(&self.payload), self.port)
+    }
+}
+
+/// Return the topic to which downlinks for the device are published.
+pub fn topic(app_id: &str, dev_id: &str) -> String {
+    format!("{}/devices/{}/down", app_id, dev_id)
+}
+
+/// How the configuration commands of the ax-sense are encoded.
+///
+/// A configuration downlink is sent on `port` and consists of the register
+/// of the setting followed by the new value as big endian u16. The port and
+/// the registers are taken from the documentation of the sensor, there are
+/// no defaults.
+#[derive(Debug, PartialEq, Clone)]
+pub struct AxSenseLayout {
+    pub port: u8,
+    /// Registers of the settings, by the name of the setting (`interval`,
+    /// `keepalive` and `threshold`).
+    pub registers: HashMap<String, u8>,
+}
+
+/// Configuration commands for the ax-sense.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum AxSenseCommand {
+    /// Interval between two distance measurements in seconds.
+    MeasurementInterval(u64),
+    /// Interval between two keepalive messages in seconds. The device only
+    /// supports whole minutes.
+    KeepaliveInterval(u64),
+    /// Distance below which the device considers the mailbox full, in
+    /// millimeters.
+    DistanceThreshold(u16),
+}
+
+impl AxSenseCommand {
+    /// Return the name of the setting, as used by the `set` command.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            AxSenseCommand::MeasurementInterval(_) => "interval",
+            AxSenseCommand::KeepaliveInterval(_) => "keepalive",
+            AxSenseCommand::DistanceThreshold(_) => "threshold",
+        }
+    }
+
+    pub fn downlink(&self, layout: &AxSenseLayout) -> Result<Downlink, String> {
+        let register = *layout.registers.get(self.name())
+            .ok_or_else(|| format!("No register configured for the {} setting", self.name()))?;
+        let value = match *self {
+            AxSenseCommand::MeasurementInterval(secs) => secs,
+            AxSenseCommand::KeepaliveInterval(secs) => secs / 60,
+            AxSenseCommand::DistanceThreshold(mm) => mm as u64,
+        };
+        if value == 0 || value > u16::max_value() as u64 {
+            return Err(format!("Value out of range: {}", self));
+        }
+        Ok(Downlink {
+            port: layout.port,
+            payload: vec![register, (value >> 8) as u8, value as u8],
+            confirmed: true,
+        })
+    }
+}
+
+impl fmt::Display for AxSenseCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AxSenseCommand::MeasurementInterval(secs) => write!(f, "measurement interval {}s", secs),
+            AxSenseCommand::KeepaliveInterval(secs) => write!(f, "keepalive interval {}min", secs / 60),
+            AxSenseCommand::DistanceThreshold(mm) => write!(f, "distance threshold {}mm", mm),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DownlinkQueue {
+    queues: HashMap<String, VecDeque<Downlink>>,
+}
+
+impl DownlinkQueue {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Queue a downlink for the device and return the number of queued
+    /// downlinks.
+    pub fn push(&mut self, deveui: &str, downlink: Downlink) -> usize {
+        let queue = self.queues.entry(deveui.to_owned()).or_insert_with(VecDeque::new);
+        queue.push_back(downlink);
+        queue.len()
+    }
+
+    /// Return the next downlink for the device.
+    pub fn pop(&mut self, deveui: &str) -> Option<Downlink> {
+        self.queues.get_mut(deveui).and_then(|queue| queue.pop_front())
+    }
+
+    /// Put a downlink that could not be sent back to the front of the queue.
+    pub fn requeue(&mut self, deveui: &str, downlink: Downlink) {
+        self.queues.entry(deveui.to_owned()).or_insert_with(VecDeque::new).push_front(downlink);
+    }
+
+    pub fn len(&self, deveui: &str) -> usize {
+        self.queues.get(deveui).map_or(0, |queue| queue.len())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_downlink() {
+        let downlink = Downlink { port: 100, payload: vec![0x02, 0x01, 0x68], confirmed: true };
+        assert_eq!(downlink.to_json(), json!({"port": 100, "confirmed": true, "payload_raw": "AgFo"}));
+        assert_eq!(downlink.to_string(), "downlink 020168 on port 100");
+        assert_eq!(topic("smartmail", "mailbox"), "smartmail/devices/mailbox/down");
+    }
+
+    #[test]
+    fn test_ax_sense_command() {
+        let layout = AxSenseLayout {
+            port: 100,
+            registers: vec![("interval".to_owned(), 1), ("keepalive".to_owned(), 2)].into_iter().collect(),
+        };
+        let downlink = AxSenseCommand::KeepaliveInterval(6 * 3600).downlink(&layout).unwrap();
+        assert_eq!(downlink, Downlink { port: 100, payload: vec![0x02, 0x01, 0x68], confirmed: true });
+        assert_eq!(AxSenseCommand::MeasurementInterval(300).downlink(&layout).unwrap().payload, vec![0x01, 0x01, 0x2c]);
+
+        assert!(AxSenseCommand::MeasurementInterval(0).downlink(&layout).is_err());
+        assert!(AxSenseCommand::MeasurementInterval(100000).downlink(&layout).is_err());
+        assert!(AxSenseCommand::DistanceThreshold(200).downlink(&layout).is_err());
+    }
+
+    #[test]
+    fn test_queue() {
+        let mut queue = DownlinkQueue::new();
+        let downlink = |port| Downlink { port: port, payload: vec![], confirmed: false };
+        assert_eq!(queue.pop("0004A30B001A2B3C"), None);
+        assert_eq!(queue.push("0004A30B001A2B3C", downlink(1)), 1);
+        assert_eq!(queue.push("0004A30B001A2B3C", downlink(2)), 2);
+        assert_eq!(queue.len("0004A30B001A2B3D"), 0);
+        assert_eq!(queue.pop("0004A30B001A2B3C"), Some(downlink(1)));
+        assert_eq!(queue.len("0004A30B001A2B3C"), 1);
+        queue.requeue("0004A30B001A2B3C", downlink(1));
+        assert_eq!(queue.pop("0004A30B001A2B3C"), Some(downlink(1)));
+    }
+}
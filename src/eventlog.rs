@@ -0,0 +1,347 @@
+//! A persistent log of uplinks, measurements, state transitions and
+//! notification deliveries.
+//!
+//! The entries are appended to a file with one JSON object per line and kept
+//! in memory for queries. Entries older than the retention period are pruned
+//! periodically.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::{self, Value};
+
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Record {
+    Uplink {
+        port: u64,
+        counter: u64,
+        rssi: Option<f32>,
+        snr: Option<f32>,
+        data_rate: Option<String>,
+    },
+    /// A decoded value, e.g. `distance` (in mm), `temperature` or `voltage`.
+    Measurement { name: String, value: f64 },
+    /// The device changed to a new state, e.g. `full`, `empty`, `offline`,
+    /// `online`, `battery_low` or `coverage_poor`.
+    Transition { state: String },
+    /// A notification about an event (or a retry from the outbox if `event`
+    /// is `None`) was sent, queued, held, suppressed or dropped.
+    Notification { event: Option<String>, recipient: String, status: String },
+    /// A recipient (`by`, with the display `name`) claimed to have picked up
+    /// the mail.
+    Claim { by: String, name: String },
+}
+
+impl Record {
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Record::Uplink { .. } => "uplink",
+            Record::Measurement { .. } => "measurement",
+            Record::Transition { .. } => "transition",
+            Record::Notification { .. } => "notification",
+            Record::Claim { .. } => "claim",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Entry {
+    /// Unix timestamp.
+    pub timestamp: u64,
+    pub deveui: Option<String>,
+    pub record: Record,
+}
+
+impl Entry {
+    pub fn to_json(&self) -> Value {
+        let mut val = match self.record {
+            Record::Uplink { port, counter, rssi, snr, ref data_rate } => json!({
+                "port": port,
+                "counter": counter,
+                "rssi": rssi,
+                "snr": snr,
+                "data_rate": data_rate,
+            }),
+            Record::Measurement { ref name, value } => json!({
+                "name": name,
+                "value": value,
+            }),
+            Record::Transition { ref state } => json!({
+                "state": state,
+            }),
+            Record::Notification { ref event, ref recipient, ref status } => json!({
+                "event": event,
+                "recipient": recipient,
+                "status": status,
+            }),
+            Record::Claim { ref by, ref name } => json!({
+                "by": by,
+                "name": name,
+            }),
+        };
+        val["type"] = json!(self.record.kind());
+        val["timestamp"] = json!(self.timestamp);
+        val["deveui"] = json!(self.deveui);
+        val
+    }
+
+    /// Return the JSON of the entry without the Threema IDs of recipients,
+    /// for the unauthenticated `/events` endpoint.
+    pub fn to_public_json(&self) -> Value {
+        let mut val = self.to_json();
+        if let Some(obj) = val.as_object_mut() {
+            obj.remove("recipient");
+            obj.remove("by");
+        }
+        val
+    }
+
+    fn from_json(val: &Value) -> Option<Entry> {
+        let str_field = |key: &str| val.get(key).and_then(|v| v.as_str()).map(|v| v.to_owned());
+        let f32_field = |key: &str| val.get(key).and_then(|v| v.as_f64()).map(|v| v as f32);
+        let record = match val.get("type")?.as_str()? {
+            "uplink" => Record::Uplink {
+                port: val.get("port")?.as_u64()?,
+                counter: val.get("counter")?.as_u64()?,
+                rssi: f32_field("rssi"),
+                snr: f32_field("snr"),
+                data_rate: str_field("data_rate"),
+            },
+            "measurement" => Record::Measurement {
+                name: str_field("name")?,
+                value: val.get("value")?.as_f64()?,
+            },
+            "transition" => Record::Transition {
+                state: str_field("state")?,
+            },
+            "notification" => Record::Notification {
+                event: str_field("event"),
+                recipient: str_field("recipient")?,
+                status: str_field("status")?,
+            },
+            "claim" => Record::Claim {
+                by: str_field("by")?,
+                name: str_field("name")?,
+            },
+            _ => return None,
+        };
+        Some(Entry {
+            timestamp: val.get("timestamp")?.as_u64()?,
+            deveui: str_field("deveui"),
+            record: record,
+        })
+    }
+}
+
+/// Filter for the entries of the log. All conditions are optional.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Query {
+    pub deveui: Option<String>,
+    /// The kind of the record, e.g. `transition`.
+    pub kind: Option<String>,
+    /// Unix timestamps of the oldest and newest entry.
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    /// Return at most this many of the newest matching entries.
+    pub limit: Option<usize>,
+}
+
+impl Query {
+    /// Parse a URL query string like `deveui=0004A30B001A2B3C&type=transition&limit=10`.
+    pub fn parse(query: &str) -> Result<Query, String> {
+        let mut res = Query::default();
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let mut parts = pair.splitn(2, '=');
+            let key = parts.next().unwrap_or("");
+            let val = parts.next().unwrap_or("");
+            let number = || val.parse::<u64>().map_err(|_| format!("Invalid {}: {}", key, val));
+            match key {
+                "deveui" => res.deveui = Some(val.to_owned()),
+                "type" => res.kind = Some(val.to_owned()),
+                "since" => res.since = Some(number()?),
+                "until" => res.until = Some(number()?),
+                "limit" => res.limit = Some(number()? as usize),
+                _ => return Err(format!("Unknown parameter: {}", key)),
+            }
+        }
+        Ok(res)
+    }
+
+    fn matches(&self, entry: &Entry) -> bool {
+        self.deveui.as_ref().map_or(true, |d| entry.deveui.as_ref().map_or(false, |e| e.eq_ignore_ascii_case(d)))
+            && self.kind.as_ref().map_or(true, |k| k == entry.record.kind())
+            && self.since.map_or(true, |t| entry.timestamp >= t)
+            && self.until.map_or(true, |t| entry.timestamp <= t)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct EventLog {
+    path: Option<PathBuf>,
+    file: Option<File>,
+    entries: Vec<Entry>,
+}
+
+impl EventLog {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Load the entries from the specified file.
+    ///
+    /// The path is remembered, new entries are appended to it. A missing
+    /// file is not an error.
+    pub fn load(&mut self, path: &Path) -> Result<(), String> {
+        if let Ok(file) = File::open(path) {
+            for line in BufReader::new(file).lines() {
+                let line = line.map_err(|e| format!("Could not read event log {}: {}", path.display(), e))?;
+                match serde_json::from_str(&line).ok().and_then(|val| Entry::from_json(&val)) {
+                    Some(entry) => self.entries.push(entry),
+                    None => warn!("Ignoring invalid event log entry: {}", line),
+                }
+            }
+        }
+        self.path = Some(path.to_owned());
+        self.file = Some(Self::open(path)?);
+        Ok(())
+    }
+
+    fn open(path: &Path) -> Result<File, String> {
+        OpenOptions::new().create(true).append(true).open(path)
+            .map_err(|e| format!("Could not open event log {}: {}", path.display(), e))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn append(&mut self, entry: Entry) {
+        if let Some(ref mut file) = self.file {
+            if let Err(e) = writeln!(file, "{}", entry.to_json()) {
+                error!("Could not write to event log: {}", e);
+            }
+        }
+        self.entries.push(entry);
+    }
+
+    /// Return the matching entries, oldest first.
+    pub fn query(&self, query: &Query) -> Vec<&Entry> {
+        let mut entries: Vec<&Entry> = self.entries.iter().rev()
+            .filter(|e| query.matches(e))
+            .take(query.limit.unwrap_or(usize::max_value()))
+            .collect();
+        entries.reverse();
+        entries
+    }
+
+    /// Remove all entries that are older than `retention` seconds and
+    /// return their number.
+    pub fn prune(&mut self, now: u64, retention: u64) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|e| now.saturating_sub(e.timestamp) <= retention);
+        let pruned = before - self.entries.len();
+        if pruned > 0 {
+            self.rewrite();
+        }
+        pruned
+    }
+
+    /// Replace the file with the remaining entries.
+    fn rewrite(&mut self) {
+        let path = match self.path {
+            Some(ref path) => path.clone(),
+            None => return,
+        };
+        let tmp_path = path.with_extension("tmp");
+        let res = File::create(&tmp_path)
+            .and_then(|mut file| {
+                for entry in &self.entries {
+                    writeln!(file, "{}", entry.to_json())?;
+                }
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp_path, &path))
+            .map_err(|e| format!("Could not write event log {}: {}", path.display(), e))
+            .and_then(|_| Self::open(&path));
+        match res {
+            Ok(file) => self.file = Some(file),
+            Err(e) => error!("{}", e),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transition(timestamp: u64, deveui: &str, state: &str) -> Entry {
+        Entry {
+            timestamp: timestamp,
+            deveui: Some(deveui.to_owned()),
+            record: Record::Transition { state: state.to_owned() },
+        }
+    }
+
+    #[test]
+    fn test_json() {
+        let entries = vec![
+            transition(1000, "0004A30B001A2B3C", "full"),
+            Entry {
+                timestamp: 1001,
+                deveui: Some("0004A30B001A2B3C".into()),
+                record: Record::Uplink { port: 102, counter: 42, rssi: Some(-98.0), snr: None, data_rate: Some("SF7BW125".into()) },
+            },
+            Entry {
+                timestamp: 1002,
+                deveui: None,
+                record: Record::Notification { event: None, recipient: "ECHOECHO".into(), status: "sent".into() },
+            },
+            Entry {
+                timestamp: 1003,
+                deveui: Some("0004A30B001A2B3C".into()),
+                record: Record::Claim { by: "ECHOECHO".into(), name: "Alice".into() },
+            },
+        ];
+        for entry in entries {
+            assert_eq!(Entry::from_json(&entry.to_json()), Some(entry));
+        }
+        assert_eq!(transition(1000, "0004A30B001A2B3C", "full").to_json(),
+                   json!({"type": "transition", "timestamp": 1000, "deveui": "0004A30B001A2B3C", "state": "full"}));
+
+        let claim = Entry {
+            timestamp: 1003,
+            deveui: None,
+            record: Record::Claim { by: "ECHOECHO".into(), name: "Alice".into() },
+        };
+        assert_eq!(claim.to_public_json(), json!({"type": "claim", "timestamp": 1003, "deveui": null, "name": "Alice"}));
+    }
+
+    #[test]
+    fn test_query_and_prune() {
+        let mut log = EventLog::new();
+        log.append(transition(1000, "0004A30B001A2B3C", "full"));
+        log.append(Entry {
+            timestamp: 1500,
+            deveui: Some("0004A30B001A2B3C".into()),
+            record: Record::Measurement { name: "distance".into(), value: 120.0 },
+        });
+        log.append(transition(2000, "0004A30B001A2B3D", "full"));
+        log.append(transition(3000, "0004A30B001A2B3C", "empty"));
+
+        let query = Query::parse("deveui=0004a30b001a2b3c&type=transition").unwrap();
+        let states: Vec<u64> = log.query(&query).iter().map(|e| e.timestamp).collect();
+        assert_eq!(states, vec![1000, 3000]);
+        let query = Query { limit: Some(2), ..Query::default() };
+        let states: Vec<u64> = log.query(&query).iter().map(|e| e.timestamp).collect();
+        assert_eq!(states, vec![2000, 3000]);
+        assert_eq!(log.query(&Query::parse("since=1200&until=2500").unwrap()).len(), 2);
+        assert!(Query::parse("limit=all").is_err());
+        assert!(Query::parse("foo=bar").is_err());
+
+        assert_eq!(log.prune(3000, 1000), 2);
+        assert_eq!(log.len(), 2);
+    }
+}
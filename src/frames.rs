@@ -0,0 +1,120 @@
+//! Frame counter tracking.
+
+use std::collections::VecDeque;
+
+
+/// Number of recent frame counters that are remembered for duplicate
+/// detection.
+static RECENT_FRAMES: usize = 16;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum FrameCheck {
+    /// The first frame since startup (or since the device joined).
+    First,
+    /// The frame directly follows the previous frame.
+    Ok,
+    /// A frame with this counter was already received, or the counter is
+    /// lower than the previous counter without being small enough for a
+    /// reset, e.g. because the frame was delivered twice or replayed.
+    Duplicate,
+    /// The specified number of frames between the previous frame and this
+    /// frame were lost.
+    Gap(u64),
+    /// The counter is lower than the previous counter and close to zero, i.e.
+    /// the device rebooted or rejoined.
+    Reset,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct FrameCounter {
+    /// The most recent frame counters, the last one at the back.
+    recent: VecDeque<u64>,
+}
+
+impl FrameCounter {
+    /// Check a received frame counter and remember it unless it is a
+    /// duplicate.
+    ///
+    /// Only a small counter is accepted as a reset, so that replaying an old
+    /// frame does not make the device's next frames look like duplicates.
+    pub fn check(&mut self, counter: u64) -> FrameCheck {
+        let window = RECENT_FRAMES as u64;
+        let last = match self.recent.back() {
+            Some(&last) => last,
+            None => {
+                self.recent.push_back(counter);
+                return FrameCheck::First;
+            },
+        };
+        // A small counter far below the last one means that the device
+        // rebooted without rejoining, even if the counter is still known
+        let rebooted = counter < window && last >= counter + window;
+        if !rebooted && self.recent.contains(&counter) {
+            return FrameCheck::Duplicate;
+        }
+        let check = if counter == last + 1 {
+            FrameCheck::Ok
+        } else if counter > last {
+            FrameCheck::Gap(counter - last - 1)
+        } else if counter < window {
+            FrameCheck::Reset
+        } else {
+            return FrameCheck::Duplicate;
+        };
+        if check == FrameCheck::Reset {
+            self.recent.clear();
+        }
+        self.recent.push_back(counter);
+        while self.recent.len() > RECENT_FRAMES {
+            self.recent.pop_front();
+        }
+        check
+    }
+
+    /// Forget all frame counters, e.g. after the device joined.
+    pub fn reset(&mut self) {
+        self.recent.clear();
+    }
+
+    #[cfg(test)]
+    pub fn last(&self) -> Option<u64> {
+        self.recent.back().cloned()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let mut frames = FrameCounter::default();
+        assert_eq!(frames.check(100), FrameCheck::First);
+        assert_eq!(frames.check(101), FrameCheck::Ok);
+        assert_eq!(frames.check(101), FrameCheck::Duplicate);
+        assert_eq!(frames.check(104), FrameCheck::Gap(2));
+        assert_eq!(frames.check(100), FrameCheck::Duplicate);
+        assert_eq!(frames.check(105), FrameCheck::Ok);
+        assert_eq!(frames.check(0), FrameCheck::Reset);
+        assert_eq!(frames.check(1), FrameCheck::Ok);
+        assert_eq!(frames.last(), Some(1));
+
+        frames.reset();
+        assert_eq!(frames.check(0), FrameCheck::First);
+
+        // Reboot without rejoin while the old counters are still known
+        assert_eq!(frames.check(1), FrameCheck::Ok);
+        assert_eq!(frames.check(40), FrameCheck::Gap(38));
+        assert_eq!(frames.check(0), FrameCheck::Reset);
+        assert_eq!(frames.check(1), FrameCheck::Ok);
+        assert_eq!(frames.check(1), FrameCheck::Duplicate);
+
+        // An old counter that is no longer remembered is a replay, not a reset
+        frames.reset();
+        assert_eq!(frames.check(500), FrameCheck::First);
+        assert_eq!(frames.check(100), FrameCheck::Duplicate);
+        assert_eq!(frames.last(), Some(500));
+        assert_eq!(frames.check(501), FrameCheck::Ok);
+    }
+}
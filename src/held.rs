@@ -0,0 +1,150 @@
+//! Notifications that are held back during quiet hours.
+//!
+//! Held notifications are persisted to a JSON file, so that they are still
+//! sent when the quiet hours end after a restart.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::{self, Value};
+
+
+#[derive(Debug, Default)]
+pub struct Held {
+    path: Option<PathBuf>,
+    /// The held notifications per recipient, with the Unix timestamp at which
+    /// they were held back.
+    messages: BTreeMap<String, Vec<(u64, String)>>,
+}
+
+impl Held {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Load held notifications from the specified file.
+    ///
+    /// The path is remembered, all later changes are written back to it. A
+    /// missing file is not an error.
+    pub fn load(&mut self, path: &Path) -> Result<(), String> {
+        self.path = Some(path.to_owned());
+        let mut contents = String::new();
+        match File::open(path) {
+            Ok(mut file) => {
+                file.read_to_string(&mut contents)
+                    .map_err(|e| format!("Could not read held notifications file {}: {}", path.display(), e))?;
+            },
+            Err(_) => return Ok(()),
+        };
+        let decoded: Value = serde_json::from_str(&contents)
+            .map_err(|e| format!("Could not parse held notifications file {}: {}", path.display(), e))?;
+        let entries = decoded.as_array()
+            .ok_or_else(|| format!("Held notifications file {} does not contain an array", path.display()))?;
+        for val in entries {
+            match from_json(val) {
+                Some((to, timestamp, msg)) => self.messages.entry(to).or_insert_with(Vec::new).push((timestamp, msg)),
+                None => warn!("Ignoring invalid held notification: {}", val),
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the number of held notifications.
+    pub fn len(&self) -> usize {
+        self.messages.values().map(|msgs| msgs.len()).sum()
+    }
+
+    /// Hold a notification back until the quiet hours of the recipient end.
+    pub fn hold(&mut self, to: &str, msg: &str, now: u64) {
+        self.messages.entry(to.to_owned()).or_insert_with(Vec::new).push((now, msg.to_owned()));
+        self.persist();
+    }
+
+    /// Remove and return the notifications of all recipients for which
+    /// `ended` returns `true`.
+    pub fn release<F>(&mut self, ended: F) -> Vec<(String, Vec<(u64, String)>)> where F: Fn(&str) -> bool {
+        let recipients: Vec<String> = self.messages.keys()
+            .filter(|recipient| ended(recipient))
+            .cloned()
+            .collect();
+        let released: Vec<_> = recipients.into_iter()
+            .filter_map(|recipient| self.messages.remove(&recipient).map(|msgs| (recipient, msgs)))
+            .collect();
+        if !released.is_empty() {
+            self.persist();
+        }
+        released
+    }
+
+    /// Write all held notifications to disk.
+    ///
+    /// The file is replaced atomically, like the outbox.
+    fn persist(&self) {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return,
+        };
+        let json = Value::Array(self.messages.iter()
+            .flat_map(|(to, msgs)| msgs.iter().map(move |&(timestamp, ref msg)| json!({
+                "to": to,
+                "timestamp": timestamp,
+                "msg": msg,
+            })))
+            .collect());
+        let tmp_path = path.with_extension("tmp");
+        let res = File::create(&tmp_path)
+            .and_then(|mut file| file.write_all(json.to_string().as_bytes()).and_then(|_| file.sync_all()))
+            .and_then(|_| fs::rename(&tmp_path, path));
+        if let Err(e) = res {
+            error!("Could not write held notifications file {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn from_json(val: &Value) -> Option<(String, u64, String)> {
+    Some((
+        val.get("to")?.as_str()?.to_owned(),
+        val.get("timestamp")?.as_u64()?,
+        val.get("msg")?.as_str()?.to_owned(),
+    ))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    #[test]
+    fn test_hold_and_release() {
+        let path = env::temp_dir().join("smartmail-test-held.json");
+        let _ = fs::remove_file(&path);
+
+        let mut held = Held::new();
+        held.load(&path).unwrap();
+        held.hold("ECHOECHO", "Mailbox is full!", 1000);
+        held.hold("ECHOECHO", "Mailbox was emptied.", 1010);
+        held.hold("ABCDEFGH", "Mailbox is full!", 1000);
+        assert_eq!(held.len(), 3);
+
+        // The notifications survive a restart
+        let mut loaded = Held::new();
+        loaded.load(&path).unwrap();
+        assert_eq!(loaded.len(), 3);
+
+        let released = loaded.release(|recipient| recipient == "ECHOECHO");
+        assert_eq!(released, vec![("ECHOECHO".to_owned(), vec![
+            (1000, "Mailbox is full!".to_owned()),
+            (1010, "Mailbox was emptied.".to_owned()),
+        ])]);
+        assert!(loaded.release(|recipient| recipient == "ECHOECHO").is_empty());
+
+        let mut loaded = Held::new();
+        loaded.load(&path).unwrap();
+        assert_eq!(loaded.len(), 1);
+        let _ = fs::remove_file(&path);
+    }
+}
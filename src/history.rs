@@ -0,0 +1,165 @@
+//! In-memory history of mailbox events.
+
+use std::collections::VecDeque;
+use std::fmt;
+
+
+/// Maximum number of events that are kept.
+static MAX_EVENTS: usize = 1000;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum EventKind {
+    Full,
+    Empty,
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            EventKind::Full => "full",
+            EventKind::Empty => "empty",
+        })
+    }
+}
+
+/// Somebody who claimed to have picked up the mail.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Claim {
+    /// Threema ID of the recipient.
+    pub by: String,
+    /// Display name of the recipient (nickname or Threema ID).
+    pub name: String,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Event {
+    pub id: u64,
+    pub deveui: String,
+    pub kind: EventKind,
+    pub timestamp: u64,
+    pub claim: Option<Claim>,
+    /// IDs of the Threema messages that notified about this event.
+    pub message_ids: Vec<String>,
+}
+
+impl Event {
+    fn claim(&mut self, by: &str, name: &str, now: u64) -> Result<Event, ClaimError> {
+        if let Some(ref claim) = self.claim {
+            return Err(ClaimError::AlreadyClaimed(claim.clone()));
+        }
+        self.claim = Some(Claim {
+            by: by.to_owned(),
+            name: name.to_owned(),
+            timestamp: now,
+        });
+        Ok(self.clone())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ClaimError {
+    /// No "empty" event happened within the claim window.
+    NothingToClaim,
+    /// The last "empty" event was already claimed, possibly by the same
+    /// person.
+    AlreadyClaimed(Claim),
+}
+
+#[derive(Debug, Default)]
+pub struct History {
+    events: VecDeque<Event>,
+    next_id: u64,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Record a new event and return its ID.
+    pub fn record(&mut self, deveui: &str, kind: EventKind, now: u64) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.events.push_back(Event {
+            id: id,
+            deveui: deveui.to_owned(),
+            kind: kind,
+            timestamp: now,
+            claim: None,
+            message_ids: vec![],
+        });
+        while self.events.len() > MAX_EVENTS {
+            self.events.pop_front();
+        }
+        id
+    }
+
+    /// Claim the most recent "empty" event that happened at most `window`
+    /// seconds ago.
+    pub fn claim(&mut self, by: &str, name: &str, now: u64, window: u64) -> Result<Event, ClaimError> {
+        let event = self.events.iter_mut()
+            .rev()
+            .find(|e| e.kind == EventKind::Empty)
+            .and_then(|e| if now.saturating_sub(e.timestamp) <= window { Some(e) } else { None })
+            .ok_or(ClaimError::NothingToClaim)?;
+        event.claim(by, name, now)
+    }
+
+    /// Claim the "empty" event that the specified Threema message notified
+    /// about.
+    pub fn claim_message(&mut self, msg_id: &str, by: &str, name: &str, now: u64) -> Result<Event, ClaimError> {
+        let event = self.events.iter_mut()
+            .find(|e| e.kind == EventKind::Empty && e.message_ids.iter().any(|id| id.eq_ignore_ascii_case(msg_id)))
+            .ok_or(ClaimError::NothingToClaim)?;
+        event.claim(by, name, now)
+    }
+
+    /// Remember that a Threema message notified about the specified event.
+    pub fn add_message(&mut self, event_id: u64, msg_id: &str) {
+        if let Some(event) = self.events.iter_mut().find(|e| e.id == event_id) {
+            event.message_ids.push(msg_id.to_lowercase());
+        }
+    }
+
+    pub fn iter<'a>(&'a self) -> ::std::collections::vec_deque::Iter<'a, Event> {
+        self.events.iter()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_claim() {
+        let mut history = History::new();
+        assert_eq!(history.claim("ECHOECHO", "Alice", 1000, 3600), Err(ClaimError::NothingToClaim));
+
+        history.record("0004A30B001A2B3C", EventKind::Full, 1000);
+        history.record("0004A30B001A2B3C", EventKind::Empty, 2000);
+        assert_eq!(history.claim("ECHOECHO", "Alice", 9000, 3600), Err(ClaimError::NothingToClaim));
+
+        history.add_message(1, "0123456789ABCDEF");
+        assert_eq!(
+            history.claim_message("0123456789abcdef", "ABCDEFGH", "Bob", 2100).unwrap().id,
+            1
+        );
+        assert!(history.claim("ECHOECHO", "Alice", 2500, 3600).is_err());
+        let mut history = History::new();
+        history.record("0004A30B001A2B3C", EventKind::Empty, 2000);
+        let event = history.claim("ECHOECHO", "Alice", 2500, 3600).unwrap();
+        assert_eq!(event.id, 0);
+        assert_eq!(event.claim.unwrap().name, "Alice");
+        for &(by, name) in &[("ECHOECHO", "Alice"), ("ABCDEFGH", "Bob")] {
+            match history.claim(by, name, 2600, 3600) {
+                Err(ClaimError::AlreadyClaimed(claim)) => {
+                    assert_eq!(claim.by, "ECHOECHO");
+                    assert_eq!(claim.timestamp, 2500);
+                },
+                res => panic!("Unexpected result: {:?}", res),
+            }
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! Republishing of the decoded device state to a local MQTT broker, with
+//! Home Assistant MQTT discovery.
+
+use std::collections::HashSet;
+
+use chrono::{TimeZone, Utc};
+use rumqtt::{MqttClient, QoS};
+use serde_json::Value;
+
+use device::DeviceState;
+
+
+/// The entities of a device: component, object ID, name, value template and
+/// additional discovery attributes.
+static ENTITIES: &'static [(&'static str, &'static str, &'static str, &'static str, &'static [(&'static str, &'static str)])] = &[
+    ("binary_sensor", "full", "Mailbox full", "{{ 'ON' if value_json.full else 'OFF' }}",
+     &[("icon", "mdi:mailbox")]),
+    ("sensor", "distance", "Distance", "{{ value_json.distance }}",
+     &[("unit_of_measurement", "cm"), ("icon", "mdi:ruler")]),
+    ("sensor", "voltage", "Battery voltage", "{{ value_json.voltage }}",
+     &[("unit_of_measurement", "V"), ("device_class", "voltage")]),
+    ("sensor", "temperature", "Temperature", "{{ value_json.temperature }}",
+     &[("unit_of_measurement", "°C"), ("device_class", "temperature")]),
+    ("sensor", "rssi", "RSSI", "{{ value_json.rssi }}",
+     &[("unit_of_measurement", "dBm"), ("device_class", "signal_strength")]),
+    ("sensor", "last_seen", "Last seen", "{{ value_json.last_seen }}",
+     &[("device_class", "timestamp")]),
+];
+
+/// Return the topic of the state of a device.
+pub fn state_topic(prefix: &str, deveui: &str) -> String {
+    format!("{}/{}/state", prefix, deveui)
+}
+
+/// Return the decoded state of a device. Unknown values are `null`.
+pub fn state_json(state: &DeviceState, calibration_margin: u16) -> Value {
+    json!({
+        "full": state.is_full(calibration_margin),
+        "distance": state.last_distance.map(|dist| dist as f32 / 10.0),
+        "voltage": state.last_voltage,
+        "temperature": state.last_temperature,
+        "rssi": state.last_rssi,
+        "last_seen": state.last_seen.map(|ts| Utc.timestamp(ts as i64, 0).to_rfc3339()),
+    })
+}
+
+/// Return the discovery topics and configs of all entities of a device.
+pub fn discovery_configs(discovery_prefix: &str, prefix: &str, deveui: &str, name: &str) -> Vec<(String, Value)> {
+    ENTITIES.iter().map(|&(component, object_id, entity_name, template, attributes)| {
+        let node_id = format!("smartmail_{}", deveui);
+        let mut config = json!({
+            "name": format!("{} {}", name, entity_name),
+            "unique_id": format!("{}_{}", node_id, object_id),
+            "state_topic": state_topic(prefix, deveui),
+            "value_template": template,
+            "device": {
+                "identifiers": [node_id.as_str()],
+                "name": name,
+                "manufacturer": "adnexo",
+                "model": "ax-sense",
+            },
+        });
+        if let Some(config) = config.as_object_mut() {
+            for &(key, value) in attributes {
+                config.insert(key.to_owned(), json!(value));
+            }
+        }
+        (format!("{}/{}/{}/{}/config", discovery_prefix, component, node_id, object_id), config)
+    }).collect()
+}
+
+/// Publishes the state of the devices as retained messages.
+pub struct Publisher {
+    client: MqttClient,
+    prefix: String,
+    discovery_prefix: Option<String>,
+    /// Devices whose discovery configs were published.
+    announced: HashSet<String>,
+}
+
+impl Publisher {
+    pub fn new(client: MqttClient, prefix: &str, discovery_prefix: Option<&str>) -> Publisher {
+        Publisher {
+            client: client,
+            prefix: prefix.to_owned(),
+            discovery_prefix: discovery_prefix.map(|p| p.to_owned()),
+            announced: HashSet::new(),
+        }
+    }
+
+    /// Publish the state of a device, preceded by the discovery configs when
+    /// the device is published for the first time.
+    pub fn publish(&mut self, deveui: &str, name: &str, state: &Value) -> Result<(), String> {
+        if !self.announced.contains(deveui) {
+            if let Some(ref discovery_prefix) = self.discovery_prefix {
+                for (topic, config) in discovery_configs(discovery_prefix, &self.prefix, deveui, name) {
+                    self.client.retained_publish(topic.as_str(), QoS::AtLeastOnce, config.to_string().into_bytes())
+                        .map_err(|e| format!("Could not publish discovery config to {}: {:?}", topic, e))?;
+                }
+            }
+            self.announced.insert(deveui.to_owned());
+        }
+        let topic = state_topic(&self.prefix, deveui);
+        self.client.retained_publish(topic.as_str(), QoS::AtLeastOnce, state.to_string().into_bytes())
+            .map_err(|e| format!("Could not publish state to {}: {:?}", topic, e))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state() {
+        let mut state = DeviceState::default();
+        assert_eq!(state_json(&state, 50), json!({
+            "full": null, "distance": null, "voltage": null, "temperature": null, "rssi": null, "last_seen": null,
+        }));
+        state.last_distance = Some(125);
+        state.last_voltage = Some(3.5);
+        state.last_seen = Some(1515614891);
+        let json = state_json(&state, 50);
+        assert_eq!(json["full"], json!(true));
+        assert_eq!(json["distance"], json!(12.5));
+        assert_eq!(json["last_seen"], json!("2018-01-10T20:08:11+00:00"));
+    }
+
+    #[test]
+    fn test_discovery() {
+        let configs = discovery_configs("homeassistant", "smartmail", "0004A30B001A2B3C", "Front door");
+        assert_eq!(configs.len(), ENTITIES.len());
+        let (ref topic, ref config) = configs[0];
+        assert_eq!(topic, "homeassistant/binary_sensor/smartmail_0004A30B001A2B3C/full/config");
+        assert_eq!(config["name"], json!("Front door Mailbox full"));
+        assert_eq!(config["unique_id"], json!("smartmail_0004A30B001A2B3C_full"));
+        assert_eq!(config["state_topic"], json!("smartmail/0004A30B001A2B3C/state"));
+        assert_eq!(config["device"]["identifiers"], json!(["smartmail_0004A30B001A2B3C"]));
+        let (ref topic, ref config) = configs[2];
+        assert_eq!(topic, "homeassistant/sensor/smartmail_0004A30B001A2B3C/voltage/config");
+        assert_eq!(config["device_class"], json!("voltage"));
+    }
+}
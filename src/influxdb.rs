@@ -0,0 +1,480 @@
+//! Batched writes to InfluxDB.
+//!
+//! Points are collected in a buffer and written by a background thread, so
+//! that receiving uplinks never waits for InfluxDB. While InfluxDB is
+//! unreachable, the buffered points are spooled to disk and the writes are
+//! retried with exponential backoff. Points that InfluxDB rejects are dropped.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use reqwest::{Client, StatusCode, Url};
+use reqwest::header::Authorization;
+
+use config::InfluxConfig;
+use outbox::backoff;
+
+
+/// Maximum number of lines in one write request.
+static BATCH_SIZE: usize = 500;
+
+/// Maximum number of buffered lines. The oldest lines are dropped when the
+/// buffer is full.
+static MAX_BUFFERED: usize = 100_000;
+
+/// The HTTP API used for writing.
+#[derive(Debug, PartialEq, Clone)]
+pub enum InfluxApi {
+    /// InfluxDB 1.x `/write` endpoint with basic auth.
+    V1 { user: String, pass: String, db: String },
+    /// InfluxDB 2.x `/api/v2/write` endpoint with token auth, also supported
+    /// by InfluxDB 3.x.
+    V2 { org: String, bucket: String, token: String },
+}
+
+/// The precision of the timestamps that are written.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Precision {
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+}
+
+impl Precision {
+    /// Return the value of the `precision` parameter for the API.
+    fn param(&self, api: &InfluxApi) -> &'static str {
+        match (*self, api) {
+            (Precision::Nanoseconds, &InfluxApi::V1 { .. }) => "n",
+            (Precision::Nanoseconds, &InfluxApi::V2 { .. }) => "ns",
+            (Precision::Microseconds, &InfluxApi::V1 { .. }) => "u",
+            (Precision::Microseconds, &InfluxApi::V2 { .. }) => "us",
+            (Precision::Milliseconds, _) => "ms",
+            (Precision::Seconds, _) => "s",
+        }
+    }
+
+    /// Convert the nanosecond timestamp at the end of a line.
+    fn convert(&self, line: &str) -> String {
+        let divisor = match *self {
+            Precision::Nanoseconds => return line.to_owned(),
+            Precision::Microseconds => 1_000,
+            Precision::Milliseconds => 1_000_000,
+            Precision::Seconds => 1_000_000_000,
+        };
+        let mut parts = line.rsplitn(2, ' ');
+        match (parts.next().and_then(|ts| ts.parse::<u64>().ok()), parts.next()) {
+            (Some(timestamp), Some(rest)) => format!("{} {}", rest, timestamp / divisor),
+            _ => line.to_owned(),
+        }
+    }
+}
+
+impl FromStr for Precision {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        match val {
+            "ns" => Ok(Precision::Nanoseconds),
+            "us" => Ok(Precision::Microseconds),
+            "ms" => Ok(Precision::Milliseconds),
+            "s" => Ok(Precision::Seconds),
+            _ => Err(format!("Invalid precision (expected ns, us, ms or s): {}", val)),
+        }
+    }
+}
+
+/// Escape the characters that have a special meaning in the line protocol.
+fn escape(val: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(val.len());
+    for c in val.chars() {
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// A point with tags and numeric fields.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Point {
+    measurement: String,
+    tags: Vec<(String, String)>,
+    fields: Vec<(String, f64)>,
+    /// Nanoseconds since the Unix epoch.
+    timestamp: u64,
+}
+
+impl Point {
+    /// Create a point with the current time as timestamp.
+    pub fn new(measurement: &str) -> Point {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards");
+        Point {
+            measurement: measurement.to_owned(),
+            tags: vec![],
+            fields: vec![],
+            timestamp: now.as_secs() * 1_000_000_000 + now.subsec_nanos() as u64,
+        }
+    }
+
+    /// Add a tag. Tags with empty values are not allowed by InfluxDB and
+    /// are skipped.
+    pub fn tag(mut self, key: &str, value: &str) -> Point {
+        if !value.is_empty() {
+            self.tags.push((key.to_owned(), value.to_owned()));
+        }
+        self
+    }
+
+    /// Add a field. Values that are not finite are skipped.
+    pub fn field(mut self, key: &str, value: f64) -> Point {
+        if value.is_finite() {
+            self.fields.push((key.to_owned(), value));
+        }
+        self
+    }
+
+    /// Set the timestamp in nanoseconds since the Unix epoch.
+    pub fn timestamp(mut self, timestamp: u64) -> Point {
+        self.timestamp = timestamp;
+        self
+    }
+
+    pub fn has_fields(&self) -> bool {
+        !self.fields.is_empty()
+    }
+
+    /// Return the point in line protocol format, with a nanosecond timestamp.
+    pub fn to_line(&self) -> String {
+        let mut line = escape(&self.measurement, &[',', ' ']);
+        for &(ref key, ref value) in &self.tags {
+            line.push_str(&format!(",{}={}", escape(key, &[',', '=', ' ']), escape(value, &[',', '=', ' '])));
+        }
+        let fields: Vec<String> = self.fields.iter()
+            .map(|&(ref key, value)| format!("{}={}", escape(key, &[',', '=', ' ']), value))
+            .collect();
+        format!("{} {} {}", line, fields.join(","), self.timestamp)
+    }
+}
+
+/// Points in line protocol format (with nanosecond timestamps) that are
+/// waiting to be written.
+#[derive(Debug, Default)]
+pub struct WriteBuffer {
+    path: Option<PathBuf>,
+    lines: VecDeque<String>,
+    /// Number of consecutive failed writes.
+    attempts: u32,
+    /// Unix timestamp of the next write attempt after a failure.
+    next_attempt: u64,
+    /// Whether the spool file contains lines.
+    spooled: bool,
+    /// Number of lines that were dropped because the buffer was full.
+    pub dropped: u64,
+}
+
+impl WriteBuffer {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Load the lines that were spooled to the specified file.
+    ///
+    /// The path is remembered and used for spooling. A missing file is not
+    /// an error.
+    pub fn load(&mut self, path: &Path) -> Result<(), String> {
+        self.path = Some(path.to_owned());
+        let mut contents = String::new();
+        match File::open(path) {
+            Ok(mut file) => {
+                file.read_to_string(&mut contents)
+                    .map_err(|e| format!("Could not read InfluxDB buffer file {}: {}", path.display(), e))?;
+            },
+            Err(_) => return Ok(()),
+        };
+        for line in contents.lines().filter(|line| !line.is_empty()) {
+            self.push(line.to_owned());
+        }
+        self.spooled = !self.lines.is_empty();
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn push(&mut self, line: String) {
+        self.lines.push_back(line);
+        while self.lines.len() > MAX_BUFFERED {
+            self.lines.pop_front();
+            self.dropped += 1;
+        }
+    }
+
+    /// Return the next batch of lines if a write is due.
+    pub fn batch(&self, now: u64) -> Option<Vec<String>> {
+        if self.lines.is_empty() || now < self.next_attempt {
+            return None;
+        }
+        Some(self.lines.iter().take(BATCH_SIZE).cloned().collect())
+    }
+
+    /// Remove the specified number of lines after they were written.
+    pub fn written(&mut self, count: usize) {
+        for _ in 0..count {
+            self.lines.pop_front();
+        }
+        self.attempts = 0;
+        self.next_attempt = 0;
+        if self.spooled {
+            self.spool();
+        }
+    }
+
+    /// Record a failed write, schedule the next attempt and spool the
+    /// buffered lines to disk.
+    pub fn write_failed(&mut self, now: u64) -> u64 {
+        self.attempts += 1;
+        let delay = backoff(self.attempts);
+        self.next_attempt = now + delay;
+        self.spool();
+        delay
+    }
+
+    /// Write the buffered lines to disk, or remove the file if the buffer is
+    /// empty.
+    fn spool(&mut self) {
+        let path = match self.path {
+            Some(ref path) => path.clone(),
+            None => return,
+        };
+        let res = if self.lines.is_empty() {
+            match fs::remove_file(&path) {
+                Err(ref e) if e.kind() != ::std::io::ErrorKind::NotFound => Err(format!("{}", e)),
+                _ => Ok(()),
+            }
+        } else {
+            let mut contents = String::new();
+            for line in &self.lines {
+                contents.push_str(line);
+                contents.push('\n');
+            }
+            let tmp_path = path.with_extension("tmp");
+            File::create(&tmp_path)
+                .and_then(|mut file| file.write_all(contents.as_bytes()).and_then(|_| file.sync_all()))
+                .and_then(|_| fs::rename(&tmp_path, &path))
+                .map_err(|e| format!("{}", e))
+        };
+        match res {
+            Ok(()) => self.spooled = !self.lines.is_empty(),
+            Err(e) => error!("Could not write InfluxDB buffer file {}: {}", path.display(), e),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum WriteError {
+    /// The write may succeed later, e.g. after a network error, a server
+    /// error, rate limiting or an authentication failure.
+    Temporary(String),
+    /// InfluxDB rejected the points, e.g. because of an invalid line, a field
+    /// type conflict or a too large request. Retrying won't help.
+    Rejected(String),
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WriteError::Temporary(ref e) | WriteError::Rejected(ref e) => f.write_str(e),
+        }
+    }
+}
+
+/// Writes batches of points to InfluxDB, reusing one HTTP client.
+pub struct Writer {
+    client: Client,
+    url: Url,
+    conf: InfluxConfig,
+}
+
+impl Writer {
+    pub fn new(conf: InfluxConfig) -> Result<Writer, String> {
+        let client = Client::new().map_err(|e| format!("Could not create reqwest::Client instance: {}", e))?;
+        let precision = conf.precision.param(&conf.api);
+        let url = match conf.api {
+            InfluxApi::V1 { ref db, .. } => Url::parse_with_params(
+                &format!("{}/write", conf.url),
+                &[("db", db.as_str()), ("precision", precision)],
+            ),
+            InfluxApi::V2 { ref org, ref bucket, .. } => Url::parse_with_params(
+                &format!("{}/api/v2/write", conf.url),
+                &[("org", org.as_str()), ("bucket", bucket.as_str()), ("precision", precision)],
+            ),
+        }.map_err(|e| format!("Invalid InfluxDB URL {}: {}", conf.url, e))?;
+        Ok(Writer { client: client, url: url, conf: conf })
+    }
+
+    /// Write the lines in one request.
+    pub fn write(&self, lines: &[String]) -> Result<(), WriteError> {
+        let body: Vec<String> = lines.iter().map(|line| self.conf.precision.convert(line)).collect();
+        let mut builder = self.client.post(self.url.as_str())
+            .map_err(|e| WriteError::Temporary(format!("Could not create reqwest::RequestBuilder instance: {}", e)))?;
+        builder.body(body.join("\n"));
+        match self.conf.api {
+            InfluxApi::V1 { ref user, ref pass, .. } => builder.basic_auth(user.clone(), Some(pass.clone())),
+            InfluxApi::V2 { ref token, .. } => builder.header(Authorization(format!("Token {}", token))),
+        };
+        match builder.send().map(|response| response.status()) {
+            Ok(status) if status == StatusCode::NoContent => Ok(()),
+            Ok(status) if status.is_client_error() && ![401, 403, 429].contains(&status.as_u16()) =>
+                Err(WriteError::Rejected(format!("Rejected with status {}", status))),
+            Ok(status) => Err(WriteError::Temporary(format!("Unexpected status: {}", status))),
+            Err(e) => Err(WriteError::Temporary(format!("{}", e))),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc;
+    use std::thread;
+
+    use tiny_http::{Response, Server};
+
+    /// Start a mock InfluxDB that answers one request with the status and
+    /// return its URL and the received URL, authorization header and body.
+    fn mock_server(status: u16) -> (String, mpsc::Receiver<(String, Option<String>, String)>) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut request = server.recv().unwrap();
+            let auth = request.headers().iter()
+                .find(|h| h.field.equiv("Authorization"))
+                .map(|h| h.value.as_str().to_owned());
+            let mut body = String::new();
+            request.as_reader().read_to_string(&mut body).unwrap();
+            tx.send((request.url().to_owned(), auth, body)).unwrap();
+            request.respond(Response::empty(status)).unwrap();
+        });
+        (url, rx)
+    }
+
+    fn lines() -> Vec<String> {
+        vec![
+            "uplink,deveui=0004A30B001A2B3C counter=42 1515614891123456789".to_owned(),
+            "outbox pending=0,failed=0 1515614892000000000".to_owned(),
+        ]
+    }
+
+    #[test]
+    fn test_line() {
+        let point = Point::new("uplink")
+            .tag("deveui", "0004A30B001A2B3C")
+            .tag("device", "Mailbox, front door")
+            .tag("port", "")
+            .field("counter", 42.0)
+            .field("snr", -7.25)
+            .field("rssi", ::std::f64::NAN)
+            .timestamp(1515614891123456789);
+        assert_eq!(point.to_line(), "uplink,deveui=0004A30B001A2B3C,device=Mailbox\\,\\ front\\ door counter=42,snr=-7.25 1515614891123456789");
+        assert_eq!(escape("a=b", &[',', '=', ' ']), "a\\=b");
+        assert!(!Point::new("joins").has_fields());
+    }
+
+    #[test]
+    fn test_precision() {
+        assert_eq!("ms".parse(), Ok(Precision::Milliseconds));
+        assert!("h".parse::<Precision>().is_err());
+        assert_eq!(Precision::Seconds.convert("outbox pending=0 1515614892000000000"), "outbox pending=0 1515614892");
+        assert_eq!(Precision::Nanoseconds.convert("outbox pending=0 1515614892000000000"), "outbox pending=0 1515614892000000000");
+    }
+
+    #[test]
+    fn test_write_v1() {
+        let (url, rx) = mock_server(204);
+        let writer = Writer::new(InfluxConfig {
+            url: url,
+            api: InfluxApi::V1 { user: "smartmail".into(), pass: "secret".into(), db: "mailbox".into() },
+            precision: Precision::Nanoseconds,
+            buffer_path: "influxdb.buffer".into(),
+        }).unwrap();
+        assert_eq!(writer.write(&lines()), Ok(()));
+        let (url, auth, body) = rx.recv().unwrap();
+        assert_eq!(url, "/write?db=mailbox&precision=n");
+        assert!(auth.unwrap().starts_with("Basic "));
+        assert_eq!(body, lines().join("\n"));
+    }
+
+    #[test]
+    fn test_write_v2() {
+        let (url, rx) = mock_server(204);
+        let writer = Writer::new(InfluxConfig {
+            url: url,
+            api: InfluxApi::V2 { org: "home".into(), bucket: "smartmail".into(), token: "s3cr3t".into() },
+            precision: Precision::Seconds,
+            buffer_path: "influxdb.buffer".into(),
+        }).unwrap();
+        assert_eq!(writer.write(&lines()), Ok(()));
+        let (url, auth, body) = rx.recv().unwrap();
+        assert_eq!(url, "/api/v2/write?org=home&bucket=smartmail&precision=s");
+        assert_eq!(auth, Some("Token s3cr3t".to_owned()));
+        assert_eq!(body, "uplink,deveui=0004A30B001A2B3C counter=42 1515614891\noutbox pending=0,failed=0 1515614892");
+    }
+
+    #[test]
+    fn test_write_error() {
+        let (url, _rx) = mock_server(401);
+        let writer = Writer::new(InfluxConfig {
+            url: url,
+            api: InfluxApi::V2 { org: "home".into(), bucket: "smartmail".into(), token: "invalid".into() },
+            precision: Precision::Nanoseconds,
+            buffer_path: "influxdb.buffer".into(),
+        }).unwrap();
+        match writer.write(&lines()) {
+            Err(WriteError::Temporary(_)) => {},
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        let (url, _rx) = mock_server(400);
+        let writer = Writer::new(InfluxConfig {
+            url: url,
+            api: InfluxApi::V2 { org: "home".into(), bucket: "smartmail".into(), token: "secret".into() },
+            precision: Precision::Nanoseconds,
+            buffer_path: "influxdb.buffer".into(),
+        }).unwrap();
+        match writer.write(&lines()) {
+            Err(WriteError::Rejected(_)) => {},
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_batches() {
+        let mut buffer = WriteBuffer::new();
+        assert_eq!(buffer.batch(0), None);
+        for i in 0..(BATCH_SIZE + 1) {
+            buffer.push(format!("counter,deveui=0004A30B001A2B3C value={}", i));
+        }
+        let batch = buffer.batch(0).unwrap();
+        assert_eq!(batch.len(), BATCH_SIZE);
+        assert_eq!(batch[0], "counter,deveui=0004A30B001A2B3C value=0");
+
+        assert_eq!(buffer.write_failed(1000), 30);
+        assert_eq!(buffer.batch(1029), None);
+        assert_eq!(buffer.batch(1030).map(|b| b.len()), Some(BATCH_SIZE));
+        assert_eq!(buffer.write_failed(1030), 60);
+
+        buffer.written(BATCH_SIZE);
+        assert_eq!(buffer.batch(1031), Some(vec![format!("counter,deveui=0004A30B001A2B3C value={}", BATCH_SIZE)]));
+    }
+}
@@ -1,4 +1,5 @@
 extern crate byteorder;
+extern crate chrono;
 extern crate data_encoding;
 extern crate dotenv;
 extern crate env_logger;
@@ -8,106 +9,461 @@ extern crate mqtt3;
 extern crate regex;
 extern crate reqwest;
 extern crate rumqtt;
-extern crate serde_json;
+#[macro_use] extern crate serde_json;
+extern crate sodiumoxide;
 extern crate threema_gateway;
+extern crate tiny_http;
 
+mod battery;
+mod callback;
+mod commands;
 mod config;
+mod device;
+mod downlink;
+mod eventlog;
+mod frames;
+mod held;
+mod history;
+mod homeassistant;
+mod influxdb;
 mod lpp;
+mod metrics;
+mod outbox;
+mod radio;
+mod ratelimit;
+mod receipts;
+mod routing;
+mod schedule;
+mod statistics;
+mod temperature;
+mod templates;
+mod ttn;
 
+use std::path::Path;
 use std::process::exit;
 use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::thread;
 
-use data_encoding::BASE64;
+use chrono::{DateTime, Local};
 use dotenv::dotenv;
 use mqtt3::Publish;
-use regex::Regex;
-use reqwest::{Client, StatusCode};
 use rumqtt::{MqttOptions, ReconnectOptions, SecurityOptions};
 use rumqtt::{MqttClient, QoS, Packet};
 use serde_json::Value;
 use threema_gateway::{ApiBuilder, E2eApi, RecipientKey};
+use tiny_http::{Header, Method, Request, Response, Server};
 
-use config::{Config, InfluxConfig};
+use battery::BatteryLevel;
+use callback::{IncomingMessage, RecentMessages};
+use commands::Command;
+use config::Config;
+use device::Devices;
+use downlink::{Downlink, DownlinkQueue};
+use eventlog::{Entry, EventLog, Query, Record};
+use frames::FrameCheck;
+use held::Held;
+use history::{ClaimError, EventKind, History};
+use homeassistant::Publisher;
+use influxdb::{Point, WriteBuffer, WriteError};
 use lpp::{LppDecoder, Channel, DataType};
+use metrics::Counters;
+use outbox::Outbox;
+use radio::{AdrIssue, AIRTIME_BUDGET};
+use ratelimit::{Decision, RateLimiter};
+use receipts::{DeliveryLog, DeliveryStatus};
+use routing::{Backend, EventType, Recipient};
+use schedule::QuietAction;
+use statistics::{DigestSchedule, Statistics};
+use temperature::TemperatureState;
+use templates::TemplateKind;
+use ttn::{Activation, DataRate, Topic, Uplink};
 
 
 lazy_static! {
-    static ref LAST_DISTANCE: Mutex<Option<u16>> = Mutex::new(None);
-    static ref LAST_VOLTAGE: Mutex<Option<f32>> = Mutex::new(None);
-    static ref LAST_TEMPERATURE: Mutex<Option<f32>> = Mutex::new(None);
-    static ref DATA_RATE_RE: Regex = Regex::new(r"^SF(\d+)BW(\d+)$").unwrap();
+    static ref DEVICES: Mutex<Devices> = Mutex::new(Devices::new());
+    /// Unix timestamp until which notifications are paused.
+    static ref MUTED_UNTIL: Mutex<Option<u64>> = Mutex::new(None);
+    static ref OUTBOX: Mutex<Outbox> = Mutex::new(Outbox::new());
+    static ref DELIVERIES: Mutex<DeliveryLog> = Mutex::new(DeliveryLog::new());
+    static ref HISTORY: Mutex<History> = Mutex::new(History::new());
+    static ref RATE_LIMITER: Mutex<RateLimiter> = Mutex::new(RateLimiter::new());
+    /// Notifications held back during quiet hours, per recipient.
+    static ref HELD: Mutex<Held> = Mutex::new(Held::new());
+    static ref COUNTERS: Mutex<Counters> = Mutex::new(Counters::new());
+    /// Points waiting to be written to InfluxDB.
+    static ref INFLUX_BUFFER: Mutex<WriteBuffer> = Mutex::new(WriteBuffer::new());
+    /// Downlinks waiting for the next uplink of the device.
+    static ref DOWNLINKS: Mutex<DownlinkQueue> = Mutex::new(DownlinkQueue::new());
+    /// The MQTT client, used to publish downlinks.
+    static ref MQTT_CLIENT: Mutex<Option<MqttClient>> = Mutex::new(None);
+    /// Republishes the device state to the local MQTT broker.
+    static ref PUBLISHER: Mutex<Option<Publisher>> = Mutex::new(None);
+    static ref EVENT_LOG: Mutex<EventLog> = Mutex::new(EventLog::new());
+    /// IDs of the incoming messages that were processed already.
+    static ref RECENT_MESSAGES: Mutex<RecentMessages> = Mutex::new(RecentMessages::new());
+    /// Unix timestamp at which the service was started, the last time a
+    /// device was seen if it did not send an uplink since.
+    static ref STARTED: u64 = unix_now();
 }
 
-/// If the distance falls below this value, the system assumes that the mailbox
-/// is non-empty.
-static THRESHOLD: u16 = 300;
+/// Interval (in seconds) in which the outbox is checked for messages that are
+/// due for another delivery attempt.
+static OUTBOX_INTERVAL: u64 = 10;
+
+/// The mail can be claimed up to this many seconds after the mailbox was
+/// emptied.
+static CLAIM_WINDOW: u64 = 24 * 3600;
+
+/// Interval (in seconds) in which held notifications are checked for the end
+/// of the quiet hours.
+static QUIET_HOURS_INTERVAL: u64 = 60;
+
+/// Interval (in seconds) in which buffered points are written to InfluxDB.
+static INFLUXDB_INTERVAL: u64 = 5;
+
+/// Interval (in seconds) in which devices are checked for missing uplinks.
+static WATCHDOG_INTERVAL: u64 = 60;
+
+/// Interval (in seconds) in which old entries are pruned from the event log.
+static EVENT_LOG_INTERVAL: u64 = 3600;
+
+/// Interval (in seconds) in which the digest schedule is checked.
+static DIGEST_INTERVAL: u64 = 60;
+
+/// Number of days covered by the `stats` command.
+static STATS_DAYS: u32 = 30;
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs()
+}
+
+fn local_time(timestamp: u64) -> DateTime<Local> {
+    DateTime::from(UNIX_EPOCH + Duration::from_secs(timestamp))
+}
 
 fn on_message(msg: Publish, threema_api: Arc<E2eApi>, conf: Arc<Config>) {
     debug!("Received publish packet");
     trace!("Packet: {:?}", msg);
 
-    let decoded: Value = serde_json::from_slice(&msg.payload).unwrap();
+    let topic = Topic::parse(&msg.topic_name);
+    if topic == Topic::Other {
+        debug!("Ignoring message on topic {}", msg.topic_name);
+        return;
+    }
+    let dev_id = ttn::dev_id(&msg.topic_name).to_owned();
+
+    let decoded: Value = match serde_json::from_slice(&msg.payload) {
+        Ok(val) => val,
+        Err(e) => {
+            notify_decode_error(&dev_id, &format!("Invalid JSON: {}", e), &threema_api, &conf);
+            return;
+        },
+    };
     debug!("Payload: {:?}", decoded);
 
-    let port = decoded.get("port")
-        .expect("Uplink does not contain \"port\" field!")
-        .as_u64()
-        .expect("The \"port\" field does not contain a number!");
-    let counter = decoded.get("counter")
-        .expect("Uplink does not contain \"counter\" field!")
-        .as_u64()
-        .expect("The \"counter\" field does not contain a number!");
-    let payload_raw = decoded.get("payload_raw")
-        .expect("Uplink does not contain \"payload_raw\" field!")
-        .as_str()
-        .expect("The \"payload_raw\" field does not contain a string!");
-    let payload_bytes = BASE64.decode(payload_raw.as_bytes())
-        .expect("Raw payload is not valid Base64!");
-    let deveui = decoded.get("hardware_serial")
-        .expect("Uplink does not contain \"hardware_serial\" field!")
-        .as_str()
-        .expect("The \"hardware_serial\" field does not contain a string!");
-    let airtime = decoded.get("metadata")
-        .expect("Uplink does not contain \"metadata\" field!")
-        .get("airtime")
-        .expect("The \"metadata\" object does not contain \"airtime\" field!")
-        .as_u64()
-        .expect("The \"metadata.airtime\" field does not contain a number!");
-    let data_rate = decoded.get("metadata")
-        .expect("Uplink does not contain \"metadata\" field!")
-        .get("data_rate")
-        .expect("The \"metadata\" object does not contain \"data_rate\" field!")
-        .as_str()
-        .expect("The \"metadata.data_rate\" field does not contain a string!");
-    let data_rate_captures = DATA_RATE_RE.captures(&data_rate)
-        .expect("Could not parse \"data_rate\" field");
-    let sf: Option<u8> = data_rate_captures.get(1).and_then(|mtch| mtch.as_str().parse().ok());
-    let bw: Option<u8> = data_rate_captures.get(2).and_then(|mtch| mtch.as_str().parse().ok());
+    match topic {
+        Topic::Uplink => match Uplink::parse(&decoded) {
+            Ok(uplink) => on_uplink(uplink, &dev_id, threema_api, conf),
+            Err(e) => notify_decode_error(&dev_id, &e, &threema_api, &conf),
+        },
+        Topic::Activation => match Activation::parse(&decoded) {
+            Ok(activation) => on_activation(activation, &threema_api, &conf),
+            Err(e) => error!("Could not parse activation of {}: {}", dev_id, e),
+        },
+        Topic::Other => {},
+    };
+}
+
+fn on_activation(activation: Activation, threema_api: &Arc<E2eApi>, conf: &Config) {
+    println!("Device {} joined with DevAddr {}", activation.deveui, activation.dev_addr);
+
+    match DEVICES.lock() {
+        Ok(mut devices) => devices.get_mut(&activation.deveui).joined(&activation.dev_addr, activation.data_rate.clone(), unix_now()),
+        Err(e) => error!("Could not lock DEVICES mutex: {}", e),
+    };
+    log_transition(&activation.deveui, "joined");
 
     // Log to InfluxDB
-    if let Some(ref influxdb) = conf.influxdb {
-        let tags = Some(format!("deveui={},port={}", deveui, port));
-        send_to_influxdb(influxdb, "counter", tags.clone(), counter as f32);
-        send_to_influxdb(influxdb, "airtime", tags.clone(), airtime as f32);
-        if let Some(val) = sf {
-            send_to_influxdb(influxdb, "sf", tags.clone(), val as f32);
+    if conf.influxdb.is_some() {
+        send_to_influxdb(Point::new("joins").tag("deveui", &activation.deveui).field("value", 1.0));
+    };
+
+    let vars = vec![
+        ("device", conf.device_name(&activation.deveui)),
+        ("deveui", activation.deveui.clone()),
+        ("dev_addr", activation.dev_addr.clone()),
+        ("data_rate", activation.data_rate.as_ref().map_or("?".into(), |dr| dr.to_string())),
+    ];
+    notify(EventType::Joined, Some(&activation.deveui), TemplateKind::Joined, &vars, None, threema_api, conf);
+}
+
+fn on_uplink(uplink: Uplink, dev_id: &str, threema_api: Arc<E2eApi>, conf: Arc<Config>) {
+    let deveui = &uplink.deveui;
+    let counter = uplink.counter;
+
+    // Points are written in batches, so they need the time of the uplink
+    let timestamp = uplink.time_nanos();
+
+    // Track the frame counter
+    let frame_check = match DEVICES.lock() {
+        Ok(mut devices) => {
+            let state = devices.get_mut(deveui);
+            let check = state.frames.check(counter);
+            match check {
+                FrameCheck::Duplicate => state.duplicate_frames += 1,
+                FrameCheck::Gap(lost) => state.lost_frames += lost,
+                FrameCheck::Reset => state.counter_resets += 1,
+                FrameCheck::First | FrameCheck::Ok => {},
+            };
+            Some(check)
+        },
+        Err(e) => {
+            error!("Could not lock DEVICES mutex: {}", e);
+            None
+        },
+    };
+    let frame_metric = match frame_check {
+        Some(FrameCheck::Duplicate) => {
+            warn!("Dropping duplicate or replayed uplink from {} (counter {})", deveui, counter);
+            Some(("frames_duplicate", 1))
+        },
+        Some(FrameCheck::Gap(lost)) => {
+            warn!("Lost {} uplink(s) from {} before counter {}", lost, deveui, counter);
+            Some(("frames_lost", lost))
+        },
+        Some(FrameCheck::Reset) => {
+            warn!("Frame counter of {} was reset to {}, the device rebooted or rejoined", deveui, counter);
+            Some(("counter_resets", 1))
+        },
+        _ => None,
+    };
+    if let (true, Some((measurement, val))) = (conf.influxdb.is_some(), frame_metric) {
+        send_to_influxdb(Point::new(measurement).tag("deveui", deveui).field("value", val as f64));
+    }
+    if frame_check == Some(FrameCheck::Duplicate) {
+        return;
+    }
+    match COUNTERS.lock() {
+        Ok(mut counters) => *counters.uplinks.entry(uplink.port).or_insert(0) += 1,
+        Err(e) => error!("Could not lock COUNTERS mutex: {}", e),
+    };
+    log_event(Some(deveui), Record::Uplink {
+        port: uplink.port,
+        counter: counter,
+        rssi: uplink.best_rssi(),
+        snr: uplink.best_snr(),
+        data_rate: uplink.data_rate.as_ref().map(|dr| dr.to_string()),
+    });
+
+    let back_online = match DEVICES.lock() {
+        Ok(mut devices) => {
+            let state = devices.get_mut(deveui);
+            let prev_seen = state.last_seen.unwrap_or(*STARTED);
+            if state.seen(unix_now()) { Some(prev_seen) } else { None }
+        },
+        Err(e) => {
+            error!("Could not lock DEVICES mutex: {}", e);
+            None
+        },
+    };
+    if let Some(prev_seen) = back_online {
+        notify_online_status(deveui, false, prev_seen, &threema_api, &conf);
+    }
+
+    // Track the link quality
+    let coverage = match DEVICES.lock() {
+        Ok(mut devices) => {
+            let state = devices.get_mut(deveui);
+            state.last_rssi = uplink.best_rssi();
+            state.last_snr = uplink.best_snr();
+            state.last_gateways = uplink.gateways.len();
+            match (state.last_rssi, state.last_snr) {
+                (Some(rssi), Some(snr)) => state.coverage.update(&conf.coverage_thresholds, rssi, snr)
+                    .map(|poor| (poor, rssi, snr)),
+                _ => None,
+            }
+        },
+        Err(e) => {
+            error!("Could not lock DEVICES mutex: {}", e);
+            None
+        },
+    };
+    if let Some((poor, rssi, snr)) = coverage {
+        notify_coverage(deveui, poor, rssi, snr, uplink.gateways.len(), &threema_api, &conf);
+    }
+
+    // Track the airtime
+    let airtime = match uplink.airtime.map(|airtime| (airtime, DEVICES.lock())) {
+        Some((airtime, Ok(mut devices))) => {
+            let now = unix_now();
+            let state = &mut devices.get_mut(deveui).airtime;
+            state.add(now, airtime);
+            Some((state.used(), state.check(now, conf.airtime_warning), state.interval(), state.projected()))
+        },
+        Some((_, Err(e))) => {
+            error!("Could not lock DEVICES mutex: {}", e);
+            None
+        },
+        None => None,
+    };
+    if let Some((used, true, Some(interval), Some(projected))) = airtime {
+        let notified = notify_airtime(deveui, used, interval, projected,
+                                      &uplink.data_rate.as_ref().map_or("?".into(), |dr| dr.to_string()),
+                                      &threema_api, &conf);
+        // Only remember the warning if it reached somebody
+        if notified.recipients > 0 {
+            match DEVICES.lock() {
+                Ok(mut devices) => devices.get_mut(deveui).airtime.warned(unix_now()),
+                Err(e) => error!("Could not lock DEVICES mutex: {}", e),
+            };
+        }
+    }
+
+    // Analyse the data rates
+    if let Some(ref data_rate) = uplink.data_rate {
+        let issues = match DEVICES.lock() {
+            Ok(mut devices) => devices.get_mut(deveui).data_rates.add(data_rate.clone()),
+            Err(e) => {
+                error!("Could not lock DEVICES mutex: {}", e);
+                vec![]
+            },
+        };
+        for issue in issues {
+            notify_adr_issue(deveui, issue, data_rate, &threema_api, &conf);
+        }
+    }
+
+    // Log to InfluxDB
+    if conf.influxdb.is_some() {
+        let mut point = uplink_point("uplink", deveui, timestamp)
+            .tag("port", &uplink.port.to_string())
+            .field("counter", counter as f64)
+            .field("gateways", uplink.gateways.len() as f64);
+        if let Some(val) = uplink.airtime {
+            point = point.field("airtime", val as f64);
         }
-        if let Some(val) = bw {
-            send_to_influxdb(influxdb, "bw", tags.clone(), val as f32);
+        if let Some(val) = uplink.data_rate.as_ref().and_then(|dr| dr.sf()) {
+            point = point.field("sf", val as f64);
         }
+        if let Some(val) = uplink.data_rate.as_ref().and_then(|dr| dr.bw()) {
+            point = point.field("bw", val as f64);
+        }
+        if let Some(val) = uplink.best_rssi() {
+            point = point.field("rssi", val as f64);
+        }
+        if let Some(val) = uplink.best_snr() {
+            point = point.field("snr", val as f64);
+        }
+        if let Some((used, _, _, _)) = airtime {
+            point = point.field("airtime_24h", used as f64 / 1e9);
+        }
+        send_to_influxdb(point);
     };
 
+    // The device listens for a downlink right after the uplink
+    send_next_downlink(deveui, dev_id, &conf);
+
     // Process depending on port
-    match port {
-        101 => process_keepalive(&payload_bytes, &deveui, threema_api, conf),
-        102 => process_distance(&payload_bytes, &deveui, threema_api, conf),
+    match uplink.port {
+        101 => process_keepalive(&uplink.payload, deveui, timestamp, threema_api, conf.clone()),
+        102 => process_distance(&uplink.payload, deveui, timestamp, threema_api, conf.clone()),
         p => info!("Received message on unknown port: {}", p),
     };
+
+    publish_state(deveui, &conf);
+}
+
+/// Republish the decoded state of the device to the local MQTT broker.
+fn publish_state(deveui: &str, conf: &Config) {
+    let state = match DEVICES.lock() {
+        Ok(devices) => match devices.get(deveui) {
+            Some(state) => homeassistant::state_json(state, conf.calibration_margin),
+            None => return,
+        },
+        Err(e) => {
+            error!("Could not lock DEVICES mutex: {}", e);
+            return;
+        },
+    };
+    match PUBLISHER.lock() {
+        Ok(mut publisher) => if let Some(ref mut publisher) = *publisher {
+            if let Err(e) = publisher.publish(deveui, &conf.device_name(deveui), &state) {
+                warn!("{}", e);
+            }
+        },
+        Err(e) => error!("Could not lock PUBLISHER mutex: {}", e),
+    };
+}
+
+/// Queue a downlink that is sent with the next uplink of the device.
+///
+/// Return the number of downlinks queued for the device.
+fn queue_downlink(deveui: &str, downlink: Downlink) -> Result<usize, String> {
+    match DOWNLINKS.lock() {
+        Ok(mut downlinks) => Ok(downlinks.push(deveui, downlink)),
+        Err(e) => Err(format!("Could not lock DOWNLINKS mutex: {}", e)),
+    }
 }
 
-fn process_distance(bytes: &[u8], deveui: &str, threema_api: Arc<E2eApi>, conf: Arc<Config>) {
+/// Queue a downlink for the specified device, or for all devices, and return
+/// the reply to the chat command.
+fn queue_command_downlink(deveui: Option<String>, downlink: Downlink, description: &str) -> String {
+    let targets: Vec<String> = match DEVICES.lock() {
+        Ok(devices) => devices.iter()
+            .map(|(d, _)| d.clone())
+            .filter(|d| deveui.as_ref().map_or(true, |wanted| d.eq_ignore_ascii_case(wanted)))
+            .collect(),
+        Err(e) => return format!("Could not lock DEVICES mutex: {}", e),
+    };
+    if targets.is_empty() {
+        return "No matching device has been seen yet.".into();
+    }
+    targets.iter()
+        .map(|d| match queue_downlink(d, downlink.clone()) {
+            Ok(queued) => format!("*{}*: Sending {} with the next uplink ({} downlink(s) queued).", d, description, queued),
+            Err(e) => format!("*{}*: {}", d, e),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Publish the next queued downlink for the device, if any.
+fn send_next_downlink(deveui: &str, dev_id: &str, conf: &Config) {
+    let downlink = match DOWNLINKS.lock() {
+        Ok(mut downlinks) => downlinks.pop(deveui),
+        Err(e) => {
+            error!("Could not lock DOWNLINKS mutex: {}", e);
+            return;
+        },
+    };
+    let downlink = match downlink {
+        Some(downlink) => downlink,
+        None => return,
+    };
+    let topic = downlink::topic(&conf.ttn_app_id, dev_id);
+    let payload = downlink.to_json().to_string();
+    let res = match MQTT_CLIENT.lock() {
+        Ok(mut client) => match *client {
+            Some(ref mut client) => client.publish(topic.as_str(), QoS::AtLeastOnce, payload.into_bytes())
+                .map_err(|e| format!("{:?}", e)),
+            None => Err("Not connected".into()),
+        },
+        Err(e) => Err(format!("Could not lock MQTT_CLIENT mutex: {}", e)),
+    };
+    match res {
+        Ok(()) => info!("Sent downlink on port {} to {}", downlink.port, deveui),
+        Err(e) => {
+            warn!("Could not send downlink to {}, requeueing: {}", deveui, e);
+            match DOWNLINKS.lock() {
+                Ok(mut downlinks) => downlinks.requeue(deveui, downlink),
+                Err(e) => error!("Could not lock DOWNLINKS mutex: {}", e),
+            };
+        },
+    }
+}
+
+fn process_distance(bytes: &[u8], deveui: &str, timestamp: Option<u64>, threema_api: Arc<E2eApi>, conf: Arc<Config>) {
     info!("Received distance measurement");
 
     // Create decoder
@@ -119,164 +475,1272 @@ fn process_distance(bytes: &[u8], deveui: &str, threema_api: Arc<E2eApi>, conf:
         _ => None,
     }).next() {
         Some(dist) => dist,
-        None => return,
+        None => {
+            notify_decode_error(deveui, "Payload does not contain a distance measurement", &threema_api, &conf);
+            return;
+        },
     };
     println!("==> Distance: {}mm", distance_mm);
+    log_measurement(deveui, "distance", distance_mm as f64);
 
     // Compare to previous measurement
-    match LAST_DISTANCE.lock() {
-        Ok(mut guard) => {
-            if let Some(prev_dist) = *guard {
-                debug!("Previous distance was {}mm", prev_dist);
-                if prev_dist < THRESHOLD && distance_mm >= THRESHOLD {
-                    notify_empty(distance_mm, prev_dist, threema_api, conf.clone());
-                } else if prev_dist >= THRESHOLD && distance_mm < THRESHOLD {
-                    notify_full(distance_mm, prev_dist, threema_api, conf.clone());
-                };
-            } else {
-                debug!("No previous distance stored");
-            };
-            *guard = Some(distance_mm);
+    let (prev_dist, threshold) = match DEVICES.lock() {
+        Ok(mut devices) => {
+            let state = devices.get_mut(deveui);
+            let prev_dist = state.last_distance;
+            state.last_distance = Some(distance_mm);
+            (prev_dist, state.threshold(conf.calibration_margin))
+        },
+        Err(e) => {
+            error!("Could not lock DEVICES mutex: {}", e);
+            (None, device::DEFAULT_THRESHOLD)
         },
-        Err(e) => error!("Could not lock LAST_DISTANCE mutex: {}", e),
+    };
+    if let Some(prev_dist) = prev_dist {
+        debug!("Previous distance was {}mm", prev_dist);
+        if prev_dist < threshold && distance_mm >= threshold {
+            let event_id = record_event(deveui, EventKind::Empty);
+            notify_empty(deveui, event_id, distance_mm, prev_dist, threema_api, conf.clone());
+        } else if prev_dist >= threshold && distance_mm < threshold {
+            record_event(deveui, EventKind::Full);
+            notify_full(deveui, distance_mm, prev_dist, threema_api, conf.clone());
+        };
+    } else {
+        debug!("No previous distance stored");
     };
 
     // Log to InfluxDB
-    if let Some(ref influxdb) = conf.influxdb {
-        let tags = Some(format!("deveui={}", deveui));
-        send_to_influxdb(influxdb, "distance", tags, distance_mm.into());
+    if conf.influxdb.is_some() {
+        send_to_influxdb(uplink_point("distance", deveui, timestamp)
+            .field("distance", distance_mm as f64)
+            .field("threshold", threshold as f64)
+            .field("full", if distance_mm < threshold { 1.0 } else { 0.0 }));
     };
 }
 
-fn process_keepalive(bytes: &[u8], deveui: &str, _threema_api: Arc<E2eApi>, conf: Arc<Config>) {
+fn process_keepalive(bytes: &[u8], deveui: &str, timestamp: Option<u64>, threema_api: Arc<E2eApi>, conf: Arc<Config>) {
     info!("Received keepalive message");
     let decoder = LppDecoder::new(bytes.iter());
-    let tags = Some(format!("deveui={}", deveui));
+    let mut point = uplink_point("keepalive", deveui, timestamp);
     for item in decoder {
         match (item.channel, item.value) {
             (Channel::DistanceSensor, DataType::Temperature(degrees)) => {
                 println!("==> Temperature: {} °C", degrees);
+                log_measurement(deveui, "temperature", degrees as f64);
 
-                match LAST_TEMPERATURE.lock() {
-                    Ok(mut guard) => *guard = Some(degrees),
-                    Err(e) => error!("Could not lock LAST_TEMPERATURE mutex: {}", e),
+                let limits = conf.temperature_limits_for(deveui);
+                let states = match DEVICES.lock() {
+                    Ok(mut devices) => {
+                        let state = devices.get_mut(deveui);
+                        let prev_state = state.temperature_state;
+                        state.last_temperature = Some(degrees);
+                        state.temperature_state = limits.state(prev_state, degrees);
+                        Some((prev_state, state.temperature_state))
+                    },
+                    Err(e) => {
+                        error!("Could not lock DEVICES mutex: {}", e);
+                        None
+                    },
                 };
-
-                // Log to InfluxDB
-                if let Some(ref influxdb) = conf.influxdb {
-                    send_to_influxdb(influxdb, "temperature", tags.clone(), degrees);
+                match states {
+                    Some((TemperatureState::Normal, TemperatureState::TooCold)) |
+                    Some((TemperatureState::TooHot, TemperatureState::TooCold)) =>
+                        notify_temperature(deveui, degrees, TemplateKind::TemperatureLow, limits.min, &threema_api, &conf),
+                    Some((TemperatureState::Normal, TemperatureState::TooHot)) |
+                    Some((TemperatureState::TooCold, TemperatureState::TooHot)) =>
+                        notify_temperature(deveui, degrees, TemplateKind::TemperatureHigh, limits.max, &threema_api, &conf),
+                    _ => {},
                 };
+                if let Some((prev_state, state)) = states {
+                    if state != prev_state {
+                        log_transition(deveui, match state {
+                            TemperatureState::Normal => "temperature_normal",
+                            TemperatureState::TooCold => "temperature_low",
+                            TemperatureState::TooHot => "temperature_high",
+                        });
+                    }
+                }
+
+                point = point.field("temperature", degrees as f64);
             },
             (Channel::Adc, DataType::AnalogInput(voltage)) => {
                 println!("==> Voltage: {} V", voltage);
+                log_measurement(deveui, "voltage", voltage as f64);
 
-                match LAST_VOLTAGE.lock() {
-                    Ok(mut guard) => *guard = Some(voltage),
-                    Err(e) => error!("Could not lock LAST_VOLTAGE mutex: {}", e),
-                };
+                let thresholds = conf.battery_thresholds_for(deveui);
+                let now = unix_now();
+                let levels = match DEVICES.lock() {
+                    Ok(mut devices) => {
+                        devices.add_voltage(deveui, now, voltage);
+                        let (prev_level, level, forecast) = {
+                            let state = devices.get_mut(deveui);
+                            let prev_level = state.battery_level;
+                            state.last_voltage = Some(voltage);
+                            state.battery_level = thresholds.level(prev_level, voltage);
 
-                // Log to InfluxDB
-                if let Some(ref influxdb) = conf.influxdb {
-                    send_to_influxdb(influxdb, "voltage", tags.clone(), voltage);
+                            // Warn once per battery before the projected cut-off
+                            let forecast = match state.voltage_history.estimate(conf.battery_cutoff, now) {
+                                Some(remaining) if remaining <= conf.battery_warning_period && !state.voltage_history.warned =>
+                                    Some(remaining),
+                                _ => None,
+                            };
+                            (prev_level, state.battery_level, forecast)
+                        };
+                        if level != prev_level {
+                            devices.save_battery_history();
+                        }
+                        Some((prev_level, level, forecast))
+                    },
+                    Err(e) => {
+                        error!("Could not lock DEVICES mutex: {}", e);
+                        None
+                    },
                 };
+                if let Some((prev_level, level, forecast)) = levels {
+                    if level != prev_level {
+                        notify_battery(deveui, voltage, level, &threema_api, &conf);
+                    }
+                    if let Some(remaining) = forecast {
+                        // Only remember the warning if it reached somebody
+                        if notify_battery_forecast(deveui, voltage, remaining, &threema_api, &conf).recipients > 0 {
+                            match DEVICES.lock() {
+                                Ok(mut devices) => {
+                                    devices.get_mut(deveui).voltage_history.warned = true;
+                                    devices.save_battery_history();
+                                },
+                                Err(e) => error!("Could not lock DEVICES mutex: {}", e),
+                            };
+                        }
+                    }
+                }
+
+                point = point.field("voltage", voltage as f64);
             },
             _ => {},
         }
     }
 
+    // Log to InfluxDB
+    if conf.influxdb.is_some() {
+        send_to_influxdb(point);
+    };
 }
 
-fn notify_full(dist: u16, prev_dist: u16, threema_api: Arc<E2eApi>, conf: Arc<Config>) {
+fn notify_full(deveui: &str, dist: u16, prev_dist: u16, threema_api: Arc<E2eApi>, conf: Arc<Config>) {
     println!("Mailbox is full! Distance changed from {}cm to {}cm", prev_dist / 10, dist / 10);
 
-    let mut msg = format!("\u{1F4EC} Mailbox is full! Distance changed from {:.1}cm to {:.1}cm.", (prev_dist as f32) / 10.0, (dist as f32) / 10.0);
-    maybe_append_stats(&mut msg);
+    let vars = template_vars(deveui, dist, prev_dist, &conf);
+    notify(EventType::Full, Some(deveui), TemplateKind::Full, &vars, None, &threema_api, &conf);
+}
+
+fn notify_empty(deveui: &str, event_id: Option<u64>, dist: u16, prev_dist: u16, threema_api: Arc<E2eApi>, conf: Arc<Config>) {
+    println!("Mailbox was emptied. Distance changed from {}cm to {}cm", prev_dist / 10, dist / 10);
 
-    for recipient in conf.threema_to.iter() {
-        threema_send(&recipient, &msg, &threema_api);
+    let vars = template_vars(deveui, dist, prev_dist, &conf);
+    let msg_ids = notify(EventType::Empty, Some(deveui), TemplateKind::Empty, &vars, None, &threema_api, &conf).msg_ids;
+    if let Some(event_id) = event_id {
+        match HISTORY.lock() {
+            Ok(mut history) => for msg_id in msg_ids {
+                history.add_message(event_id, &msg_id);
+            },
+            Err(e) => error!("Could not lock HISTORY mutex: {}", e),
+        };
     }
 }
 
-fn notify_empty(dist: u16, prev_dist: u16, threema_api: Arc<E2eApi>, conf: Arc<Config>) {
-    println!("Mailbox was emptied. Distance changed from {}cm to {}cm", prev_dist / 10, dist / 10);
+fn notify_battery(deveui: &str, voltage: f32, level: BatteryLevel, threema_api: &Arc<E2eApi>, conf: &Config) {
+    println!("Battery level of {} changed to {:?} ({}V)", deveui, level, voltage);
+
+    let (template, state) = match level {
+        BatteryLevel::Ok => (TemplateKind::BatteryOk, "battery_ok"),
+        BatteryLevel::Low => (TemplateKind::BatteryLow, "battery_low"),
+        BatteryLevel::Critical => (TemplateKind::BatteryCritical, "battery_critical"),
+    };
+    log_transition(deveui, state);
+    let vars = vec![
+        ("device", conf.device_name(deveui)),
+        ("deveui", deveui.to_owned()),
+        ("voltage", format!("{:.2}", voltage)),
+    ];
+    notify(EventType::LowBattery, Some(deveui), template, &vars, None, threema_api, conf);
+}
+
+fn notify_battery_forecast(deveui: &str, voltage: f32, remaining: u64, threema_api: &Arc<E2eApi>, conf: &Config) -> Notified {
+    println!("Battery of {} will reach the cut-off voltage in {} days", deveui, remaining / 86400);
+
+    let vars = vec![
+        ("device", conf.device_name(deveui)),
+        ("deveui", deveui.to_owned()),
+        ("voltage", format!("{:.2}", voltage)),
+        ("battery_days", (remaining / 86400).to_string()),
+    ];
+    notify(EventType::LowBattery, Some(deveui), TemplateKind::BatteryForecast, &vars, None, threema_api, conf)
+}
+
+/// Notify that a device went offline, or came back online.
+fn notify_online_status(deveui: &str, offline: bool, last_seen: u64, threema_api: &Arc<E2eApi>, conf: &Config) {
+    if offline {
+        println!("Device {} is offline, last seen {}", deveui, format_age(last_seen));
+    } else {
+        println!("Device {} is back online", deveui);
+    }
+    log_transition(deveui, if offline { "offline" } else { "online" });
+
+    let vars = vec![
+        ("device", conf.device_name(deveui)),
+        ("deveui", deveui.to_owned()),
+        ("last_seen", local_time(last_seen).format("%Y-%m-%d %H:%M").to_string()),
+    ];
+    let template = if offline { TemplateKind::Offline } else { TemplateKind::BackOnline };
+    notify(EventType::DeviceOffline, Some(deveui), template, &vars, None, threema_api, conf);
+}
 
-    let mut msg = format!("\u{1F4ED} Mailbox was emptied. Distance changed from {:.1}cm to {:.1}cm.", (prev_dist as f32) / 10.0, (dist as f32) / 10.0);
-    maybe_append_stats(&mut msg);
+/// Check for devices that did not send an uplink for too long.
+fn check_offline(threema_api: &Arc<E2eApi>, conf: &Config) {
+    let offline: Vec<(String, u64)> = match DEVICES.lock() {
+        Ok(mut devices) => devices.check_offline(unix_now(), conf.offline_timeout, *STARTED)
+            .into_iter()
+            .map(|deveui| {
+                let last_seen = devices.get(&deveui).and_then(|state| state.last_seen).unwrap_or(*STARTED);
+                (deveui, last_seen)
+            })
+            .collect(),
+        Err(e) => {
+            error!("Could not lock DEVICES mutex: {}", e);
+            return;
+        },
+    };
+    for (deveui, last_seen) in offline {
+        notify_online_status(&deveui, true, last_seen, threema_api, conf);
+    }
+}
+
+fn notify_temperature(deveui: &str, degrees: f32, template: TemplateKind, limit: Option<f32>,
+                      threema_api: &Arc<E2eApi>, conf: &Config) {
+    println!("Temperature of {} is out of range: {} °C", deveui, degrees);
 
-    for recipient in conf.threema_to.iter() {
-        threema_send(&recipient, &msg, &threema_api);
+    let mut vars = vec![
+        ("device", conf.device_name(deveui)),
+        ("deveui", deveui.to_owned()),
+        ("temperature", degrees.to_string()),
+    ];
+    if let Some(limit) = limit {
+        vars.push(("limit", limit.to_string()));
     }
+    notify(EventType::Temperature, Some(deveui), template, &vars, None, threema_api, conf);
+}
+
+fn notify_coverage(deveui: &str, poor: bool, rssi: f32, snr: f32, gateways: usize,
+                   threema_api: &Arc<E2eApi>, conf: &Config) {
+    if poor {
+        println!("Radio coverage of {} is poor (RSSI {}dBm, SNR {}dB)", deveui, rssi, snr);
+    } else {
+        println!("Radio coverage of {} is good again (RSSI {}dBm, SNR {}dB)", deveui, rssi, snr);
+    }
+    log_transition(deveui, if poor { "coverage_poor" } else { "coverage_ok" });
+
+    let vars = vec![
+        ("device", conf.device_name(deveui)),
+        ("deveui", deveui.to_owned()),
+        ("rssi", rssi.to_string()),
+        ("snr", snr.to_string()),
+        ("gateways", gateways.to_string()),
+    ];
+    let template = if poor { TemplateKind::CoveragePoor } else { TemplateKind::CoverageOk };
+    notify(EventType::Coverage, Some(deveui), template, &vars, None, threema_api, conf);
+}
+
+fn notify_airtime(deveui: &str, used: u64, interval: u64, projected: u64, data_rate: &str,
+                  threema_api: &Arc<E2eApi>, conf: &Config) -> Notified {
+    println!("Device {} used {:.1}s airtime in the last 24 hours", deveui, used as f32 / 1e9);
+
+    let vars = vec![
+        ("device", conf.device_name(deveui)),
+        ("deveui", deveui.to_owned()),
+        ("airtime", format!("{:.1}", used as f32 / 1e9)),
+        ("budget", (AIRTIME_BUDGET / 1_000_000_000).to_string()),
+        ("data_rate", data_rate.to_owned()),
+        ("interval", (interval / 60).to_string()),
+        ("projected", format!("{:.1}", projected as f32 / 1e9)),
+    ];
+    notify(EventType::Airtime, Some(deveui), TemplateKind::Airtime, &vars, None, threema_api, conf)
+}
+
+fn notify_adr_issue(deveui: &str, issue: AdrIssue, data_rate: &DataRate, threema_api: &Arc<E2eApi>, conf: &Config) {
+    println!("Data rate issue of {}: {:?}", deveui, issue);
+
+    let mut vars = vec![
+        ("device", conf.device_name(deveui)),
+        ("deveui", deveui.to_owned()),
+        ("data_rate", data_rate.to_string()),
+    ];
+    let template = match issue {
+        AdrIssue::StuckAtHighSf(_) => TemplateKind::AdrStuck,
+        AdrIssue::Oscillating(changes) => {
+            vars.push(("changes", changes.to_string()));
+            TemplateKind::AdrOscillating
+        },
+    };
+    notify(EventType::DataRate, Some(deveui), template, &vars, None, threema_api, conf);
+}
+
+fn notify_decode_error(device: &str, error: &str, threema_api: &Arc<E2eApi>, conf: &Config) {
+    error!("Could not decode uplink from {}: {}", device, error);
+    match COUNTERS.lock() {
+        Ok(mut counters) => counters.decode_errors += 1,
+        Err(e) => error!("Could not lock COUNTERS mutex: {}", e),
+    };
+
+    let vars = vec![
+        ("device", conf.device_name(device)),
+        ("deveui", device.to_owned()),
+        ("error", error.to_owned()),
+    ];
+    notify(EventType::DecodeError, Some(device), TemplateKind::DecodeError, &vars, None, threema_api, conf);
+}
+
+/// The outcome of a notification.
+#[derive(Debug, Default)]
+struct Notified {
+    /// IDs of the Threema messages that were sent immediately.
+    msg_ids: Vec<String>,
+    /// Number of recipients the notification was sent to, or queued or held
+    /// back for.
+    recipients: usize,
 }
 
-fn maybe_append_stats(msg: &mut String) {
-    if let (Ok(voltage_guard), Ok(temperature_guard)) = (LAST_VOLTAGE.lock(), LAST_TEMPERATURE.lock()) {
-        if let (Some(voltage), Some(temperature)) = (*voltage_guard, *temperature_guard) {
-            msg.push_str(&format!(" (_Voltage: {}V, temperature: {}°C._)", voltage, temperature));
+/// Send a notification about an event to all recipients that subscribed to
+/// it.
+///
+/// The message is rendered from the template in the language of each
+/// recipient. Muting, rate limiting and quiet hours apply to all backends
+/// except the log. Notifications are deduplicated per template, so e.g. a
+/// recovery is not suppressed by the preceding alert.
+fn notify(event: EventType, deveui: Option<&str>, template: TemplateKind, vars: &[(&str, String)],
+          except: Option<&str>, threema_api: &Arc<E2eApi>, conf: &Config) -> Notified {
+    let muted = is_muted();
+    if muted {
+        info!("Notifications are muted, not sending \"{}\" notification", event);
+    }
+
+    let mut notified = Notified::default();
+    for recipient in conf.recipients_for(event) {
+        if except == Some(recipient.address.as_str()) {
+            continue;
+        }
+        let msg = conf.templates.render(template, conf.language_for(&recipient.address), vars);
+        if recipient.backend != Backend::Log {
+            if muted {
+                log_notification(deveui, Some(event), &recipient.to_string(), "muted");
+                continue;
+            }
+            if let Some(deveui) = deveui {
+                if !check_rate_limit(deveui, &recipient.address, event, template, conf) {
+                    log_notification(Some(deveui), Some(event), &recipient.to_string(), "suppressed");
+                    continue;
+                }
+            }
+            if !check_quiet_hours(&recipient.address, &msg, conf) {
+                if conf.quiet_action_for(&recipient.address) == QuietAction::Suppress {
+                    log_notification(deveui, Some(event), &recipient.to_string(), "quiet_hours");
+                } else {
+                    log_notification(deveui, Some(event), &recipient.to_string(), "held");
+                    notified.recipients += 1;
+                }
+                continue;
+            }
+        }
+        let msg_id = send_notification(&recipient, event, &msg, threema_api);
+        let status = match msg_id {
+            Some(_) => "sent",
+            None if recipient.backend == Backend::Log => "logged",
+            None => "queued",
         };
+        log_notification(deveui, Some(event), &recipient.to_string(), status);
+        notified.recipients += 1;
+        if let Some(msg_id) = msg_id {
+            notified.msg_ids.push(msg_id);
+        }
+    }
+    notified
+}
+
+/// Send a notification through the recipient's backend.
+fn send_notification(recipient: &Recipient, event: EventType, msg: &str, threema_api: &Arc<E2eApi>) -> Option<String> {
+    match recipient.backend {
+        Backend::Threema => deliver(&recipient.address, msg, threema_api),
+        Backend::Log => {
+            info!("Notification ({}) for {}: {}", event, recipient, msg);
+            count_notification(Backend::Log, true);
+            None
+        },
+    }
+}
+
+/// Count a sent notification or a failed delivery attempt.
+fn count_notification(backend: Backend, sent: bool) {
+    match COUNTERS.lock() {
+        Ok(mut counters) => {
+            let counter = if sent { &mut counters.notifications_sent } else { &mut counters.notifications_failed };
+            *counter.entry(backend.name()).or_insert(0) += 1;
+        },
+        Err(e) => error!("Could not lock COUNTERS mutex: {}", e),
+    };
+}
+
+/// Check whether a notification about the event may be sent to the recipient
+/// without exceeding the rate limit or repeating an identical notification
+/// (with the same template).
+fn check_rate_limit(deveui: &str, recipient: &str, event: EventType, template: TemplateKind, conf: &Config) -> bool {
+    let (decision, suppressed) = match RATE_LIMITER.lock() {
+        Ok(mut limiter) => (limiter.check(deveui, recipient, template.name(), unix_now()), limiter.suppressed),
+        Err(e) => {
+            error!("Could not lock RATE_LIMITER mutex: {}", e);
+            return true;
+        },
+    };
+    let reason = match decision {
+        Decision::Allow => return true,
+        Decision::RateLimited => "rate_limit",
+        Decision::Duplicate => "duplicate",
+    };
+    warn!("Suppressing \"{}\" notification for {} to {} ({}), {} suppressed so far",
+          event, deveui, recipient, reason, suppressed);
+
+    // Log to InfluxDB
+    if conf.influxdb.is_some() {
+        send_to_influxdb(Point::new("notifications_suppressed")
+            .tag("deveui", deveui)
+            .tag("reason", reason)
+            .field("value", 1.0));
     };
+    false
 }
 
-fn threema_send(to: &str, msg: &str, threema_api: &Arc<E2eApi>) {
-    let public_key = match threema_api.lookup_pubkey(&to) {
-        Ok(pk) => pk,
+/// Check whether the recipient is in quiet hours.
+///
+/// Return `true` if the notification may be sent now. Otherwise it is either
+/// dropped or held back until the quiet hours end.
+fn check_quiet_hours(recipient: &str, msg: &str, conf: &Config) -> bool {
+    let now = Local::now().naive_local();
+    if !conf.quiet_hours.is_quiet(recipient, now) {
+        return true;
+    }
+    match conf.quiet_action_for(recipient) {
+        QuietAction::Suppress => info!("Suppressing notification to {} during quiet hours", recipient),
+        QuietAction::Delay | QuietAction::Summarise => {
+            match conf.quiet_hours.quiet_until(recipient, now) {
+                Some(until) => info!("Holding notification to {} until {}", recipient, until.format("%a %H:%M")),
+                None => info!("Holding notification to {} until the quiet hours end", recipient),
+            };
+            match HELD.lock() {
+                Ok(mut held) => held.hold(recipient, msg, unix_now()),
+                Err(e) => error!("Could not lock HELD mutex: {}", e),
+            };
+        },
+    };
+    false
+}
+
+/// Send held notifications to all recipients whose quiet hours have ended.
+fn release_held(threema_api: &Arc<E2eApi>, conf: &Config) {
+    let now = Local::now().naive_local();
+    let released: Vec<(String, Vec<(u64, String)>)> = match HELD.lock() {
+        Ok(mut held) => held.release(|recipient| !conf.quiet_hours.is_quiet(recipient, now)),
         Err(e) => {
-            error!("Could not look up public key for {}: {}", to, e);
+            error!("Could not lock HELD mutex: {}", e);
             return;
         },
     };
-    let recipient_key = match RecipientKey::from_str(&public_key) {
-        Ok(rk) => rk,
+
+    for (recipient, msgs) in released {
+        info!("Quiet hours of {} ended, releasing {} notification(s)", recipient, msgs.len());
+        if conf.quiet_action_for(&recipient) == QuietAction::Summarise && msgs.len() > 1 {
+            let vars = vec![("count", msgs.len().to_string())];
+            let mut summary = conf.templates.render(TemplateKind::Summary, conf.language_for(&recipient), &vars);
+            for &(timestamp, ref msg) in msgs.iter() {
+                summary.push_str(&format!("\n\n_{}_ {}", local_time(timestamp).format("%H:%M"), msg));
+            }
+            deliver(&recipient, &summary, threema_api);
+        } else {
+            for (_, msg) in msgs {
+                deliver(&recipient, &msg, threema_api);
+            }
+        }
+    }
+}
+
+/// Append a record to the event log.
+fn log_event(deveui: Option<&str>, record: Record) {
+    let entry = Entry {
+        timestamp: unix_now(),
+        deveui: deveui.map(|deveui| deveui.to_owned()),
+        record: record,
+    };
+    match EVENT_LOG.lock() {
+        Ok(mut log) => log.append(entry),
+        Err(e) => error!("Could not lock EVENT_LOG mutex: {}", e),
+    };
+}
+
+fn log_measurement(deveui: &str, name: &str, value: f64) {
+    log_event(Some(deveui), Record::Measurement { name: name.to_owned(), value: value });
+}
+
+fn log_transition(deveui: &str, state: &str) {
+    log_event(Some(deveui), Record::Transition { state: state.to_owned() });
+}
+
+/// Log the outcome of a notification, `event` is `None` for outbox retries.
+fn log_notification(deveui: Option<&str>, event: Option<EventType>, recipient: &str, status: &str) {
+    log_event(deveui, Record::Notification {
+        event: event.map(|event| event.name().to_owned()),
+        recipient: recipient.to_owned(),
+        status: status.to_owned(),
+    });
+}
+
+/// Add an event to the history and return its ID.
+fn record_event(deveui: &str, kind: EventKind) -> Option<u64> {
+    log_transition(deveui, &kind.to_string());
+    match HISTORY.lock() {
+        Ok(mut history) => Some(history.record(deveui, kind, unix_now())),
         Err(e) => {
-            error!("Could not process public key for {}: {}", to, e);
+            error!("Could not lock HISTORY mutex: {}", e);
+            None
+        },
+    }
+}
+
+/// Record that somebody picked up the mail and tell the other recipients.
+///
+/// Return the reply for the claimer.
+fn on_claim(res: Result<history::Event, ClaimError>, from: &str, threema_api: &Arc<E2eApi>, conf: &Config) -> String {
+    match res {
+        Ok(event) => {
+            let claim = event.claim.expect("Claimed event without claim");
+            info!("{} picked up the mail from {}", claim.name, event.deveui);
+            log_event(Some(&event.deveui), Record::Claim { by: claim.by.clone(), name: claim.name.clone() });
+            let vars = vec![
+                ("name", claim.name.clone()),
+                ("device", conf.device_name(&event.deveui)),
+                ("time", local_time(claim.timestamp).format("%H:%M").to_string()),
+            ];
+            notify(EventType::PickedUp, None, TemplateKind::PickedUp, &vars, Some(from), threema_api, conf);
+            "\u{1F44D} Thanks, the others have been notified.".into()
+        },
+        Err(ClaimError::AlreadyClaimed(claim)) => {
+            if claim.by == from {
+                "You already claimed the mail.".into()
+            } else {
+                format!("{} already picked up the mail.", claim.name)
+            }
+        },
+        Err(ClaimError::NothingToClaim) => "The mailbox has not been emptied recently.".into(),
+    }
+}
+
+/// Return the values for the template placeholders.
+///
+/// The `voltage` and `temperature` values are only included if they are
+/// known.
+fn template_vars(deveui: &str, dist: u16, prev_dist: u16, conf: &Config) -> Vec<(&'static str, String)> {
+    let now = Local::now();
+    let mut vars = vec![
+        ("device", conf.device_name(deveui)),
+        ("deveui", deveui.to_owned()),
+        ("distance", conf.distance_unit.format(dist)),
+        ("prev_distance", conf.distance_unit.format(prev_dist)),
+        ("time", now.format("%H:%M").to_string()),
+        ("date", now.format("%Y-%m-%d").to_string()),
+    ];
+    if let Ok(devices) = DEVICES.lock() {
+        if let Some(state) = devices.get(deveui) {
+            if let Some(voltage) = state.last_voltage {
+                vars.push(("voltage", voltage.to_string()));
+            }
+            if let Some(remaining) = state.voltage_history.estimate(conf.battery_cutoff, unix_now()) {
+                vars.push(("battery_days", (remaining / 86400).to_string()));
+            }
+            if let Some(temperature) = state.last_temperature {
+                vars.push(("temperature", temperature.to_string()));
+            }
+            if let Some(last_seen) = state.last_seen {
+                vars.push(("last_seen", local_time(last_seen).format("%Y-%m-%d %H:%M").to_string()));
+            }
+        };
+    };
+    vars
+}
+
+fn is_muted() -> bool {
+    match MUTED_UNTIL.lock() {
+        Ok(muted_until) => muted_until.map_or(false, |until| until > unix_now()),
+        Err(e) => {
+            error!("Could not lock MUTED_UNTIL mutex: {}", e);
+            false
+        },
+    }
+}
+
+/// Format the time since the specified unix timestamp in a human readable way.
+fn format_age(timestamp: u64) -> String {
+    let secs = unix_now().saturating_sub(timestamp);
+    if secs < 60 {
+        format!("{}s ago", secs)
+    } else if secs < 3600 {
+        format!("{} min ago", secs / 60)
+    } else if secs < 86400 {
+        format!("{}h {}min ago", secs / 3600, secs % 3600 / 60)
+    } else {
+        format!("{} days ago", secs / 86400)
+    }
+}
+
+/// Format an estimated remaining duration.
+fn format_duration(secs: u64) -> String {
+    if secs >= 14 * 86400 {
+        format!("~{} weeks", secs / (7 * 86400))
+    } else {
+        format!("~{} days", secs / 86400)
+    }
+}
+
+/// Handle a chat command and return the reply.
+fn handle_command(cmd: Command, from: &str, name: &str, threema_api: &Arc<E2eApi>, conf: &Config) -> String {
+    match cmd {
+        Command::Status => {
+            let mut reply = String::new();
+            if let Ok(muted_until) = MUTED_UNTIL.lock() {
+                match *muted_until {
+                    Some(until) if until == u64::max_value() => reply.push_str("\u{1F507} Notifications are muted.\n"),
+                    Some(until) if until > unix_now() => reply.push_str(&format!("\u{1F507} Notifications are muted for another {} min.\n", (until - unix_now()) / 60 + 1)),
+                    _ => {},
+                }
+            };
+            let devices = match DEVICES.lock() {
+                Ok(devices) => devices,
+                Err(e) => return format!("Could not lock DEVICES mutex: {}", e),
+            };
+            for (deveui, state) in devices.iter() {
+                reply.push_str(&match state.is_full(conf.calibration_margin) {
+                    Some(true) => format!("\u{1F4EC} *{}*: full", deveui),
+                    Some(false) => format!("\u{1F4ED} *{}*: empty", deveui),
+                    None => format!("\u{2753} *{}*: unknown", deveui),
+                });
+                if state.offline {
+                    reply.push_str(" (\u{1F4F4} offline)");
+                }
+                if let Some(dist) = state.last_distance {
+                    reply.push_str(&format!("\nDistance: {:.1}cm (threshold {:.1}cm)", (dist as f32) / 10.0, (state.threshold(conf.calibration_margin) as f32) / 10.0));
+                }
+                if let Some(voltage) = state.last_voltage {
+                    reply.push_str(&format!("\nVoltage: {}V", voltage));
+                    match state.battery_level {
+                        BatteryLevel::Ok => {},
+                        BatteryLevel::Low => reply.push_str(" (low)"),
+                        BatteryLevel::Critical => reply.push_str(" (critical)"),
+                    };
+                }
+                if let Some(remaining) = state.voltage_history.estimate(conf.battery_cutoff, unix_now()) {
+                    reply.push_str(&format!("\nBattery life: {}", format_duration(remaining)));
+                }
+                if let Some(temperature) = state.last_temperature {
+                    reply.push_str(&format!("\nTemperature: {}°C", temperature));
+                    match state.temperature_state {
+                        TemperatureState::Normal => {},
+                        TemperatureState::TooCold => reply.push_str(" (too cold)"),
+                        TemperatureState::TooHot => reply.push_str(" (too hot)"),
+                    };
+                }
+                if let Some(last_seen) = state.last_seen {
+                    reply.push_str(&format!("\nLast seen: {}", format_age(last_seen)));
+                }
+                if let (Some(rssi), Some(snr)) = (state.last_rssi, state.last_snr) {
+                    reply.push_str(&format!("\nLink: RSSI {}dBm, SNR {}dB, {} gateway(s){}", rssi, snr, state.last_gateways,
+                                            if state.coverage.is_poor() { " (poor coverage)" } else { "" }));
+                }
+                if state.airtime.used() > 0 {
+                    reply.push_str(&format!("\nAirtime: {:.1}s of {}s in 24h", state.airtime.used() as f32 / 1e9,
+                                            AIRTIME_BUDGET / 1_000_000_000));
+                    if let Some(projected) = state.airtime.projected() {
+                        reply.push_str(&format!(" (~{:.1}s per day)", projected as f32 / 1e9));
+                    }
+                }
+                let total: u64 = state.data_rates.distribution().map(|(_, &n)| n).sum();
+                if total > 0 {
+                    let distribution: Vec<String> = state.data_rates.distribution()
+                        .map(|(dr, &n)| format!("{} {}%", dr, n * 100 / total))
+                        .collect();
+                    reply.push_str(&format!("\nData rates: {}", distribution.join(", ")));
+                    if state.data_rates.is_stuck() {
+                        reply.push_str(" (stuck at high SF)");
+                    }
+                    if state.data_rates.is_oscillating() {
+                        reply.push_str(" (ADR oscillating)");
+                    }
+                }
+                if let Some(ref join) = state.last_join {
+                    reply.push_str(&format!("\nJoined: {} (DevAddr {}{})", format_age(join.timestamp), join.dev_addr,
+                                            join.data_rate.as_ref().map_or(String::new(), |dr| format!(", {}", dr))));
+                }
+                if let Ok(downlinks) = DOWNLINKS.lock() {
+                    if downlinks.len(deveui) > 0 {
+                        reply.push_str(&format!("\nQueued downlinks: {}", downlinks.len(deveui)));
+                    }
+                };
+                if state.lost_frames + state.duplicate_frames + state.counter_resets > 0 {
+                    reply.push_str(&format!("\nFrames: {} lost, {} duplicate, {} counter resets",
+                                            state.lost_frames, state.duplicate_frames, state.counter_resets));
+                }
+                if let Ok(history) = HISTORY.lock() {
+                    let last_empty = history.iter().rev()
+                        .find(|e| e.kind == EventKind::Empty && &e.deveui == deveui);
+                    if let Some(event) = last_empty {
+                        reply.push_str(&format!("\nLast emptied: {}", format_age(event.timestamp)));
+                        if let Some(ref claim) = event.claim {
+                            reply.push_str(&format!(" (picked up by {})", claim.name));
+                        }
+                    }
+                };
+                reply.push_str("\n\n");
+            }
+            if devices.iter().next().is_none() {
+                reply.push_str("No device has been seen yet.");
+            }
+            reply.trim().to_owned()
+        },
+        Command::Mute(duration) => {
+            let until = match duration {
+                Some(secs) => match unix_now().checked_add(secs) {
+                    Some(until) => until,
+                    None => return format!("Invalid duration: {}s", secs),
+                },
+                None => u64::max_value(),
+            };
+            match MUTED_UNTIL.lock() {
+                Ok(mut muted_until) => *muted_until = Some(until),
+                Err(e) => return format!("Could not lock MUTED_UNTIL mutex: {}", e),
+            };
+            match duration {
+                Some(secs) => format!("\u{1F507} Notifications muted for {} min.", secs / 60),
+                None => "\u{1F507} Notifications muted until you send *unmute*.".into(),
+            }
+        },
+        Command::Unmute => {
+            match MUTED_UNTIL.lock() {
+                Ok(mut muted_until) => *muted_until = None,
+                Err(e) => return format!("Could not lock MUTED_UNTIL mutex: {}", e),
+            };
+            "\u{1F50A} Notifications resumed.".into()
+        },
+        Command::Calibrate(deveui) => {
+            let mut devices = match DEVICES.lock() {
+                Ok(devices) => devices,
+                Err(e) => return format!("Could not lock DEVICES mutex: {}", e),
+            };
+            let targets: Vec<String> = devices.iter()
+                .map(|(d, _)| d.clone())
+                .filter(|d| deveui.as_ref().map_or(true, |wanted| d.eq_ignore_ascii_case(wanted)))
+                .collect();
+            if targets.is_empty() {
+                return "No matching device has been seen yet.".into();
+            }
+            targets.iter()
+                .map(|d| match devices.calibrate(d) {
+                    Some(dist) => format!("*{}*: Empty distance set to {:.1}cm.", d, (dist as f32) / 10.0),
+                    None => format!("*{}*: No distance measured yet.", d),
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        },
+        Command::Took => {
+            let res = match HISTORY.lock() {
+                Ok(mut history) => history.claim(from, name, unix_now(), CLAIM_WINDOW),
+                Err(e) => return format!("Could not lock HISTORY mutex: {}", e),
+            };
+            on_claim(res, from, threema_api, conf)
+        },
+        Command::Set(setting, deveui) => {
+            let layout = match conf.axsense_layout {
+                Some(ref layout) => layout,
+                None => return "The ax-sense settings are not configured (AXSENSE_CONFIG_PORT), use *downlink* instead.".into(),
+            };
+            match setting.downlink(layout) {
+                Ok(downlink) => queue_command_downlink(deveui, downlink, &setting.to_string()),
+                Err(e) => e,
+            }
+        },
+        Command::Downlink(downlink, deveui) => {
+            let description = downlink.to_string();
+            queue_command_downlink(deveui, downlink, &description)
+        },
+        Command::Stats(deveui) => {
+            let targets: Vec<String> = match DEVICES.lock() {
+                Ok(devices) => devices.iter()
+                    .map(|(d, _)| d.clone())
+                    .filter(|d| deveui.as_ref().map_or(true, |wanted| d.eq_ignore_ascii_case(wanted)))
+                    .collect(),
+                Err(e) => return format!("Could not lock DEVICES mutex: {}", e),
+            };
+            if targets.is_empty() {
+                return "No matching device has been seen yet.".into();
+            }
+            let since = local_midnight(STATS_DAYS - 1);
+            targets.iter()
+                .filter_map(|d| device_statistics(d, since, unix_now()).map(|stats| (d, stats)))
+                .map(|(d, stats)| {
+                    let mut reply = format!("\u{1F4CA} *{}* (last {} days)\nDeliveries: {}, {} days without mail",
+                                            d, stats.days, stats.deliveries, stats.days_without_mail);
+                    if let Some(arrival) = stats.median_arrival() {
+                        reply.push_str(&format!("\nMedian arrival: {}", statistics::format_time(arrival)));
+                    }
+                    if let Some(wait) = stats.average_wait() {
+                        reply.push_str(&format!("\nAverage wait until pickup: {}", statistics::format_wait(wait)));
+                    }
+                    if stats.deliveries > 0 {
+                        reply.push_str(&format!("\nPer weekday: {}", stats.format_weekdays()));
+                    }
+                    reply
+                })
+                .collect::<Vec<_>>()
+                .join("\n\n")
+        },
+        Command::Help => commands::HELP.into(),
+    }
+}
+
+/// Return the Unix timestamp of the local midnight the specified number of
+/// days ago.
+fn local_midnight(days_ago: u32) -> u64 {
+    let date = Local::now().date() - chrono::Duration::days(days_ago as i64);
+    // Midnight does not exist (or is ambiguous) where DST changes at midnight
+    date.and_hms_opt(0, 0, 0)
+        .or_else(|| date.and_hms_opt(1, 0, 0))
+        .map_or_else(|| unix_now().saturating_sub(days_ago as u64 * 24 * 3600), |time| time.timestamp() as u64)
+}
+
+/// Compute the delivery statistics of a device from the event log.
+fn device_statistics(deveui: &str, since: u64, until: u64) -> Option<Statistics> {
+    let query = Query {
+        deveui: Some(deveui.to_owned()),
+        kind: Some("transition".into()),
+        since: Some(since),
+        ..Query::default()
+    };
+    match EVENT_LOG.lock() {
+        Ok(log) => Some(Statistics::compute(&log.query(&query), since, until, &Local)),
+        Err(e) => {
+            error!("Could not lock EVENT_LOG mutex: {}", e);
+            None
+        },
+    }
+}
+
+/// Send the delivery statistics of every device since the start of the
+/// digest period.
+fn send_digests(schedule: DigestSchedule, threema_api: &Arc<E2eApi>, conf: &Config) {
+    let deveuis: Vec<String> = match DEVICES.lock() {
+        Ok(devices) => devices.iter().map(|(d, _)| d.clone()).collect(),
+        Err(e) => {
+            error!("Could not lock DEVICES mutex: {}", e);
             return;
         },
     };
+    let until = unix_now();
+    let since = until.saturating_sub(schedule.days() as u64 * 24 * 3600);
+    let template = match schedule {
+        DigestSchedule::Daily(_) => TemplateKind::DigestDaily,
+        DigestSchedule::Weekly(_, _) => TemplateKind::DigestWeekly,
+    };
+    for deveui in deveuis {
+        let stats = match device_statistics(&deveui, since, until) {
+            Some(stats) => stats,
+            None => continue,
+        };
+        println!("Sending digest for {}: {} deliveries", deveui, stats.deliveries);
+
+        let mut vars = vec![
+            ("device", conf.device_name(&deveui)),
+            ("deveui", deveui.clone()),
+            ("deliveries", stats.deliveries.to_string()),
+            ("days_without_mail", stats.days_without_mail.to_string()),
+        ];
+        if let (Some(arrival), Some(wait)) = (stats.median_arrival(), stats.average_wait()) {
+            vars.push(("arrival", statistics::format_time(arrival)));
+            vars.push(("wait", statistics::format_wait(wait)));
+        }
+        notify(EventType::Digest, Some(&deveui), template, &vars, None, threema_api, conf);
+    }
+}
+
+/// Send a Threema message, queue it in the outbox if sending fails.
+///
+/// Return the message ID if the message was sent immediately.
+fn deliver(to: &str, msg: &str, threema_api: &Arc<E2eApi>) -> Option<String> {
+    let res = threema_send(to, msg, threema_api);
+    count_notification(Backend::Threema, res.is_ok());
+    match res {
+        Ok(msg_id) => Some(msg_id),
+        Err(e) => {
+            warn!("{}, queueing message for retry", e);
+            match OUTBOX.lock() {
+                Ok(mut outbox) => {
+                    outbox.push(to, msg, unix_now());
+                    warn!("{} message(s) pending in outbox", outbox.len());
+                },
+                Err(e) => error!("Could not lock OUTBOX mutex, message to {} is lost: {}", to, e),
+            };
+            None
+        },
+    }
+}
+
+fn threema_send(to: &str, msg: &str, threema_api: &Arc<E2eApi>) -> Result<String, String> {
+    let public_key = threema_api.lookup_pubkey(&to)
+        .map_err(|e| format!("Could not look up public key for {}: {}", to, e))?;
+    let recipient_key = RecipientKey::from_str(&public_key)
+        .map_err(|e| format!("Could not process public key for {}: {}", to, e))?;
     let encrypted = threema_api.encrypt_text_msg(&msg, &recipient_key);
-    match threema_api.send(&to, &encrypted) {
-        Ok(msg_id) => debug!("Sent Threema message to {} ({})", to, msg_id),
-        Err(e) => error!("Could not send message to {}: {}", to, e),
+    let msg_id = threema_api.send(&to, &encrypted)
+        .map_err(|e| format!("Could not send message to {}: {}", to, e))?;
+    debug!("Sent Threema message to {} ({})", to, msg_id);
+    match DELIVERIES.lock() {
+        Ok(mut deliveries) => deliveries.sent(&msg_id, to, msg, unix_now()),
+        Err(e) => error!("Could not lock DELIVERIES mutex: {}", e),
+    };
+    Ok(msg_id)
+}
+
+/// Retry all outbox messages that are due, drop the ones that are too old.
+fn process_outbox(threema_api: &Arc<E2eApi>, conf: &Config) {
+    let now = unix_now();
+    let due = match OUTBOX.lock() {
+        Ok(mut outbox) => {
+            for entry in outbox.expire(now, conf.outbox_max_age) {
+                error!("Giving up on message to {} after {} attempts: {}", entry.to, entry.attempts, entry.msg);
+                log_notification(None, None, &entry.to, "expired");
+            }
+            outbox.due(now)
+        },
+        Err(e) => {
+            error!("Could not lock OUTBOX mutex: {}", e);
+            return;
+        },
+    };
+
+    // Send without holding the lock, so that new messages can be queued
+    for entry in due {
+        let res = threema_send(&entry.to, &entry.msg, threema_api);
+        count_notification(Backend::Threema, res.is_ok());
+        match OUTBOX.lock() {
+            Ok(mut outbox) => match res {
+                Ok(_) => {
+                    info!("Delivered queued message to {} after {} attempts", entry.to, entry.attempts + 1);
+                    outbox.delivered(entry.id);
+                    log_notification(None, None, &entry.to, "sent");
+                },
+                Err(e) => {
+                    warn!("{} (attempt {})", e, entry.attempts + 1);
+                    outbox.attempt_failed(entry.id, now);
+                },
+            },
+            Err(e) => error!("Could not lock OUTBOX mutex: {}", e),
+        };
+    }
+
+    let (pending, failed) = match OUTBOX.lock() {
+        Ok(outbox) => (outbox.len(), outbox.failed),
+        Err(_) => return,
+    };
+    if pending > 0 {
+        info!("{} message(s) pending in outbox", pending);
+    }
+
+    // Log to InfluxDB
+    if conf.influxdb.is_some() {
+        send_to_influxdb(Point::new("outbox").field("pending", pending as f64).field("failed", failed as f64));
     };
 }
 
-fn send_to_influxdb(conf: &InfluxConfig, measurement: &str, tags: Option<String>, value: f32) {
-    debug!("Sending {} to InfluxDB...", measurement);
-    let client = match Client::new() {
-        Ok(client) => client,
+/// Serve the Threema Gateway callback endpoint.
+fn run_callback_server(listen: &str, threema_api: Arc<E2eApi>, conf: Arc<Config>) {
+    let server = match Server::http(listen) {
+        Ok(server) => server,
         Err(e) => {
-            warn!("Could not create reqwest::Client instance: {}", e);
+            error!("Could not start callback server on {}: {}", listen, e);
             return;
         },
     };
-    let mut builder = match client.post(&format!("{}/write?db={}", conf.url, conf.db)) {
-        Ok(builder) => builder,
+    for request in server.incoming_requests() {
+        on_callback(request, &threema_api, &conf);
+    }
+}
+
+/// Serve the Prometheus metrics endpoint and the event log queries.
+fn run_metrics_server(listen: &str, conf: Arc<Config>) {
+    let server = match Server::http(listen) {
+        Ok(server) => server,
         Err(e) => {
-            warn!("Could not create reqwest::RequestBuilder instance: {}", e);
+            error!("Could not start metrics server on {}: {}", listen, e);
             return;
-        }
+        },
     };
-    let res = builder
-        .body(match tags {
-            Some(tags) => format!("{},{} value={}", measurement, tags, value),
-            None => format!("{} value={}", measurement, value),
-        })
-        .basic_auth(conf.user.clone(), Some(conf.pass.clone()))
-        .send();
-    match res.map(|response| response.status()) {
-        Ok(status) if status == StatusCode::NoContent => {
-            debug!("Sent {} to InfluxDB (db={})", measurement, conf.db);
+    for request in server.incoming_requests() {
+        // Only the path is compared, the query string is parsed by the handler
+        if request.method() == &Method::Get && request.url().splitn(2, '?').next() == Some("/events") {
+            on_events_request(request);
+            continue;
         }
-        Ok(status) => {
-            warn!("Unexpected status when writing {} to InfluxDB: {}", measurement, status);
+        if request.method() != &Method::Get || request.url() != "/metrics" {
+            respond(request, 404);
+            continue;
         }
-        Err(e) => {
-            warn!("Error when writing {} to InfluxDB: {}", measurement, e);
+        let body = match (DEVICES.lock(), COUNTERS.lock()) {
+            (Ok(devices), Ok(counters)) => metrics::render(&counters, &devices, conf.calibration_margin),
+            _ => {
+                error!("Could not lock DEVICES or COUNTERS mutex");
+                respond(request, 500);
+                continue;
+            },
+        };
+        let content_type = Header::from_bytes(&b"Content-Type"[..], &b"text/plain; version=0.0.4"[..])
+            .expect("Invalid header");
+        if let Err(e) = request.respond(Response::from_string(body).with_header(content_type)) {
+            warn!("Could not send metrics response: {}", e);
         }
     }
 }
 
+/// Answer a query of the event log with a JSON array of the entries.
+fn on_events_request(request: Request) {
+    let query = match request.url().splitn(2, '?').nth(1).map_or(Ok(Query::default()), Query::parse) {
+        Ok(query) => query,
+        Err(e) => {
+            warn!("Invalid event log query: {}", e);
+            respond(request, 400);
+            return;
+        },
+    };
+    let body = match EVENT_LOG.lock() {
+        Ok(log) => Value::Array(log.query(&query).iter().map(|entry| entry.to_public_json()).collect()).to_string(),
+        Err(e) => {
+            error!("Could not lock EVENT_LOG mutex: {}", e);
+            respond(request, 500);
+            return;
+        },
+    };
+    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+        .expect("Invalid header");
+    if let Err(e) = request.respond(Response::from_string(body).with_header(content_type)) {
+        warn!("Could not send event log response: {}", e);
+    }
+}
+
+fn on_callback(mut request: Request, threema_api: &Arc<E2eApi>, conf: &Config) {
+    debug!("Received callback request: {} {}", request.method(), request.url());
+    if *request.method() != Method::Post {
+        respond(request, 405);
+        return;
+    }
+    let mut body = String::new();
+    if let Err(e) = request.as_reader().read_to_string(&mut body) {
+        warn!("Could not read callback request body: {}", e);
+        respond(request, 400);
+        return;
+    }
+    let incoming = match callback::parse_request(&body, &conf.threema_secret) {
+        Ok(incoming) => incoming,
+        Err(e) => {
+            warn!("Rejecting callback request: {}", e);
+            respond(request, 400);
+            return;
+        },
+    };
+    let decrypted = threema_api.lookup_pubkey(&incoming.from)
+        .map_err(|e| format!("Could not look up public key for {}: {}", incoming.from, e))
+        .and_then(|public_key| callback::decrypt(&incoming, &public_key, &conf.threema_private_key));
+    let msg = match decrypted {
+        Ok(msg) => msg,
+        Err(e) => {
+            warn!("{}", e);
+            respond(request, 400);
+            return;
+        },
+    };
+
+    // The gateway retries requests that were not answered in time, so a
+    // command could otherwise be executed twice
+    let is_new = match RECENT_MESSAGES.lock() {
+        Ok(mut recent) => recent.insert(&incoming.message_id),
+        Err(e) => {
+            error!("Could not lock RECENT_MESSAGES mutex: {}", e);
+            true
+        },
+    };
+    if !is_new {
+        info!("Ignoring message {} from {} that was processed already", incoming.message_id, incoming.from);
+        respond(request, 200);
+        return;
+    }
+
+    match msg {
+        IncomingMessage::Text(text) => {
+            info!("Received message from {}: {}", incoming.from, text);
+            if conf.is_authorised(&incoming.from) {
+                let name = incoming.nickname.clone().unwrap_or_else(|| incoming.from.clone());
+                let reply = match commands::parse(&text) {
+                    Ok(cmd) => handle_command(cmd, &incoming.from, &name, threema_api, conf),
+                    Err(e) => format!("{}\n\n{}", e, commands::HELP),
+                };
+                if let Err(e) = threema_send(&incoming.from, &reply, threema_api) {
+                    error!("{}", e);
+                }
+            } else {
+                warn!("Ignoring message from unauthorised sender {}", incoming.from);
+            }
+        },
+        IncomingMessage::DeliveryReceipt { status, message_ids } => {
+            match DELIVERIES.lock() {
+                Ok(mut deliveries) => for msg_id in message_ids.iter() {
+                    match deliveries.receipt(msg_id, status, incoming.date) {
+                        Some(sent) => info!("Message {} to {} was {}: {}", msg_id, sent.to, sent.status, sent.msg),
+                        None => debug!("Received {} receipt for unknown message {}", status, msg_id),
+                    }
+                },
+                Err(e) => error!("Could not lock DELIVERIES mutex: {}", e),
+            };
+
+            // A "thumbs up" on the "emptied" notification claims the mail
+            if status == DeliveryStatus::Acknowledged && conf.is_authorised(&incoming.from) {
+                let name = incoming.nickname.clone().unwrap_or_else(|| incoming.from.clone());
+                for msg_id in message_ids.iter() {
+                    let res = match HISTORY.lock() {
+                        Ok(mut history) => history.claim_message(msg_id, &incoming.from, &name, unix_now()),
+                        Err(e) => {
+                            error!("Could not lock HISTORY mutex: {}", e);
+                            continue;
+                        },
+                    };
+                    if let Err(ClaimError::NothingToClaim) = res {
+                        continue;
+                    }
+                    let reply = on_claim(res, &incoming.from, threema_api, conf);
+                    if let Err(e) = threema_send(&incoming.from, &reply, threema_api) {
+                        error!("{}", e);
+                    }
+                }
+            }
+        },
+        IncomingMessage::Other(t) => {
+            debug!("Ignoring message with type {} from {}", t, incoming.from);
+        },
+    };
+    respond(request, 200);
+}
+
+fn respond(request: Request, status: u16) {
+    if let Err(e) = request.respond(Response::empty(status)) {
+        warn!("Could not send callback response: {}", e);
+    }
+}
+
+/// Queue a point for the InfluxDB writer thread.
+fn send_to_influxdb(point: Point) {
+    if !point.has_fields() {
+        return;
+    }
+    match INFLUX_BUFFER.lock() {
+        Ok(mut buffer) => buffer.push(point.to_line()),
+        Err(e) => error!("Could not lock INFLUX_BUFFER mutex: {}", e),
+    };
+}
+
+/// Create a point for a device with the time of the uplink (if known) as
+/// timestamp.
+fn uplink_point(measurement: &str, deveui: &str, timestamp: Option<u64>) -> Point {
+    let point = Point::new(measurement).tag("deveui", deveui);
+    match timestamp {
+        Some(timestamp) => point.timestamp(timestamp),
+        None => point,
+    }
+}
+
+/// Write the lines, splitting the batch to find the lines that InfluxDB
+/// rejects.
+///
+/// Return the number of rejected lines, or an error if the write should be
+/// retried. Retrying a partially written batch is harmless: every line has an
+/// explicit timestamp (the time of the uplink, or else the time the point was
+/// created, see `Point::new`), so the points that were written already are
+/// overwritten with the same values instead of being duplicated.
+fn write_influxdb(writer: &influxdb::Writer, lines: &[String]) -> Result<usize, String> {
+    match writer.write(lines) {
+        Ok(()) => Ok(0),
+        Err(WriteError::Rejected(e)) => if lines.len() == 1 {
+            warn!("InfluxDB rejected point {}: {}", lines[0], e);
+            Ok(1)
+        } else {
+            let (first, second) = lines.split_at(lines.len() / 2);
+            Ok(write_influxdb(writer, first)? + write_influxdb(writer, second)?)
+        },
+        Err(WriteError::Temporary(e)) => Err(e),
+    }
+}
+
+/// Write all buffered points to InfluxDB, in batches.
+fn flush_influxdb(writer: &influxdb::Writer) {
+    loop {
+        let now = unix_now();
+        let batch = match INFLUX_BUFFER.lock() {
+            Ok(buffer) => buffer.batch(now),
+            Err(e) => {
+                error!("Could not lock INFLUX_BUFFER mutex: {}", e);
+                return;
+            },
+        };
+        let batch = match batch {
+            Some(batch) => batch,
+            None => return,
+        };
+
+        // Write without holding the lock, so that new points can be queued
+        let res = write_influxdb(writer, &batch);
+        match INFLUX_BUFFER.lock() {
+            Ok(mut buffer) => match res {
+                Ok(rejected) => {
+                    debug!("Wrote {} point(s) to InfluxDB", batch.len() - rejected);
+                    buffer.written(batch.len());
+                    if rejected > 0 {
+                        match COUNTERS.lock() {
+                            Ok(mut counters) => counters.influxdb_write_failures += 1,
+                            Err(e) => error!("Could not lock COUNTERS mutex: {}", e),
+                        };
+                        error!("InfluxDB rejected {} of {} point(s), dropped them", rejected, batch.len());
+                    }
+                },
+                Err(e) => {
+                    let delay = buffer.write_failed(now);
+                    match COUNTERS.lock() {
+                        Ok(mut counters) => counters.influxdb_write_failures += 1,
+                        Err(e) => error!("Could not lock COUNTERS mutex: {}", e),
+                    };
+                    warn!("Could not write {} point(s) to InfluxDB, retrying in {}s: {}", buffer.len(), delay, e);
+                    return;
+                },
+            },
+            Err(e) => {
+                error!("Could not lock INFLUX_BUFFER mutex: {}", e);
+                return;
+            },
+        };
+    }
+}
+
 fn main() {
     env_logger::init();
     dotenv().ok();
+    lazy_static::initialize(&STARTED);
 
     println!("                  ____.----.");
     println!("        ____.----'          \\");
@@ -319,7 +1783,82 @@ fn main() {
         }
     );
 
+    // Load pending notifications
+    match OUTBOX.lock() {
+        Ok(mut outbox) => {
+            if let Err(msg) = outbox.load(Path::new(&conf.outbox_path)) {
+                println!("Error: {}", msg);
+                exit(1);
+            }
+            if outbox.len() > 0 {
+                println!("--> {} message(s) pending in outbox", outbox.len());
+            }
+        },
+        Err(e) => error!("Could not lock OUTBOX mutex: {}", e),
+    };
+    match HELD.lock() {
+        Ok(mut held) => {
+            if let Err(msg) = held.load(Path::new(&conf.held_path)) {
+                println!("Error: {}", msg);
+                exit(1);
+            }
+            if held.len() > 0 {
+                println!("--> {} notification(s) held until the quiet hours end", held.len());
+            }
+        },
+        Err(e) => error!("Could not lock HELD mutex: {}", e),
+    };
+
+    match EVENT_LOG.lock() {
+        Ok(mut log) => {
+            if let Err(msg) = log.load(Path::new(&conf.event_log_path)) {
+                println!("Error: {}", msg);
+                exit(1);
+            }
+            let pruned = log.prune(unix_now(), conf.event_log_retention);
+            println!("--> Loaded {} event log entries ({} pruned)", log.len(), pruned);
+        },
+        Err(e) => error!("Could not lock EVENT_LOG mutex: {}", e),
+    };
+
+    match RATE_LIMITER.lock() {
+        Ok(mut limiter) => limiter.configure(conf.rate_limit_per_hour, conf.dedup_window),
+        Err(e) => error!("Could not lock RATE_LIMITER mutex: {}", e),
+    };
+
+    // Load calibrated empty distances and battery histories
+    match DEVICES.lock() {
+        Ok(mut devices) => {
+            if let Err(msg) = devices.load_calibration(Path::new(&conf.calibration_path)) {
+                println!("Error: {}", msg);
+                exit(1);
+            }
+            if let Err(msg) = devices.load_battery_history(Path::new(&conf.battery_history_path)) {
+                println!("Error: {}", msg);
+                exit(1);
+            }
+        },
+        Err(e) => error!("Could not lock DEVICES mutex: {}", e),
+    };
+
+    // Load points that could not be written to InfluxDB before
+    if let Some(ref influxdb) = conf.influxdb {
+        match INFLUX_BUFFER.lock() {
+            Ok(mut buffer) => {
+                if let Err(msg) = buffer.load(Path::new(&influxdb.buffer_path)) {
+                    println!("Error: {}", msg);
+                    exit(1);
+                }
+                if buffer.len() > 0 {
+                    println!("--> {} point(s) pending for InfluxDB", buffer.len());
+                }
+            },
+            Err(e) => error!("Could not lock INFLUX_BUFFER mutex: {}", e),
+        };
+    }
+
     // Set up Threema Gateway API
+    let _ = sodiumoxide::init();
     let api = Arc::new(
         ApiBuilder::new(conf.threema_from.as_ref(), conf.threema_secret.as_ref())
             .with_private_key_str(&conf.threema_private_key)
@@ -355,9 +1894,134 @@ fn main() {
     println!("--> Subscribing to uplink messages...");
     let topics = vec![
         ("+/devices/+/activations", QoS::AtMostOnce),
+        ("+/devices/+/events/activations", QoS::AtMostOnce),
         ("+/devices/+/up", QoS::AtMostOnce),
     ];
     client.subscribe(topics).expect("Subcription failure");
+    match MQTT_CLIENT.lock() {
+        Ok(mut mqtt_client) => *mqtt_client = Some(client),
+        Err(e) => error!("Could not lock MQTT_CLIENT mutex: {}", e),
+    };
+
+    // Republish the device state to the local MQTT broker
+    if let Some(ref state_mqtt) = conf.state_mqtt {
+        println!("--> Connecting to local MQTT broker {}...", state_mqtt.broker);
+        let mut options = MqttOptions::new(format!("smartmail-state-{}", unix_now()), state_mqtt.broker.clone())
+            .unwrap_or_else(|e| {
+                println!("Could not initialize MqttOptions: {}", e);
+                exit(3);
+            })
+            .set_keep_alive(60)
+            .set_reconnect_opts(ReconnectOptions::Always(3));
+        if let (&Some(ref user), &Some(ref pass)) = (&state_mqtt.user, &state_mqtt.pass) {
+            options = options.set_security_opts(SecurityOptions::UsernamePassword((user.clone(), pass.clone())));
+        }
+        let (client, receiver) = MqttClient::start(options);
+        thread::spawn(move || for (packet, _userdata) in receiver {
+            trace!("Received packet from local MQTT broker: {:?}", packet);
+        });
+        match PUBLISHER.lock() {
+            Ok(mut publisher) => *publisher = Some(Publisher::new(
+                client,
+                &state_mqtt.prefix,
+                state_mqtt.discovery_prefix.as_ref().map(|p| p.as_str()),
+            )),
+            Err(e) => error!("Could not lock PUBLISHER mutex: {}", e),
+        };
+    }
+
+    // Receive incoming messages and delivery receipts
+    if let Some(ref listen) = conf.threema_callback_listen {
+        println!("--> Starting Threema callback server on {}...", listen);
+        let listen = listen.clone();
+        let api = api.clone();
+        let conf = conf.clone();
+        thread::spawn(move || run_callback_server(&listen, api, conf));
+    }
+
+    // Serve metrics for Prometheus
+    if let Some(ref listen) = conf.metrics_listen {
+        println!("--> Starting metrics server on {}...", listen);
+        let listen = listen.clone();
+        let conf = conf.clone();
+        thread::spawn(move || run_metrics_server(&listen, conf));
+    }
+
+    // Send held notifications when the quiet hours end
+    {
+        let api = api.clone();
+        let conf = conf.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(QUIET_HOURS_INTERVAL));
+            release_held(&api, &conf);
+        });
+    }
+
+    // Watch for devices that stopped sending uplinks
+    {
+        let api = api.clone();
+        let conf = conf.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(WATCHDOG_INTERVAL));
+            check_offline(&api, &conf);
+        });
+    }
+
+    // Send the delivery statistics
+    if let Some(schedule) = conf.digest_schedule {
+        let api = api.clone();
+        let conf = conf.clone();
+        thread::spawn(move || {
+            let mut prev = Local::now().naive_local();
+            loop {
+                thread::sleep(Duration::from_secs(DIGEST_INTERVAL));
+                let now = Local::now().naive_local();
+                if schedule.is_due(prev, now) {
+                    send_digests(schedule, &api, &conf);
+                }
+                prev = now;
+            }
+        });
+    }
+
+    // Prune old entries from the event log
+    {
+        let conf = conf.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(EVENT_LOG_INTERVAL));
+            match EVENT_LOG.lock() {
+                Ok(mut log) => {
+                    let pruned = log.prune(unix_now(), conf.event_log_retention);
+                    if pruned > 0 {
+                        info!("Pruned {} event log entries", pruned);
+                    }
+                },
+                Err(e) => error!("Could not lock EVENT_LOG mutex: {}", e),
+            };
+        });
+    }
+
+    // Write to InfluxDB in the background
+    if let Some(ref influxdb) = conf.influxdb {
+        let writer = influxdb::Writer::new(influxdb.clone()).unwrap_or_else(|e| {
+            println!("Could not initialize InfluxDB writer: {}", e);
+            exit(4);
+        });
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(INFLUXDB_INTERVAL));
+            flush_influxdb(&writer);
+        });
+    }
+
+    // Retry failed notifications in the background
+    {
+        let api = api.clone();
+        let conf = conf.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(OUTBOX_INTERVAL));
+            process_outbox(&api, &conf);
+        });
+    }
 
     thread::spawn(move || {
         println!("--> Listening!");
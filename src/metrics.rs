@@ -0,0 +1,129 @@
+//! Metrics in the Prometheus exposition format.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use device::{DeviceState, Devices};
+
+
+/// Process counters since startup.
+#[derive(Debug, Default)]
+pub struct Counters {
+    /// Number of uplinks per port (without duplicates).
+    pub uplinks: BTreeMap<u64, u64>,
+    pub decode_errors: u64,
+    /// Number of notifications per backend.
+    pub notifications_sent: BTreeMap<&'static str, u64>,
+    pub notifications_failed: BTreeMap<&'static str, u64>,
+    pub influxdb_write_failures: u64,
+}
+
+impl Counters {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+/// Escape a label value.
+fn escape(val: &str) -> String {
+    val.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Append a metric with its samples. Metrics without samples are skipped.
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(Option<(&str, String)>, f64)]) {
+    if samples.is_empty() {
+        return;
+    }
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for &(ref label, value) in samples {
+        let _ = match *label {
+            Some((key, ref val)) => writeln!(out, "{}{{{}=\"{}\"}} {}", name, key, escape(val), value),
+            None => writeln!(out, "{} {}", name, value),
+        };
+    }
+}
+
+/// Append a gauge with one sample per device that has a value.
+fn gauge<F>(out: &mut String, devices: &Devices, name: &str, help: &str, value: F) where F: Fn(&DeviceState) -> Option<f64> {
+    let samples: Vec<_> = devices.iter()
+        .filter_map(|(deveui, state)| value(state).map(|v| (Some(("deveui", deveui.clone())), v)))
+        .collect();
+    metric(out, name, "gauge", help, &samples);
+}
+
+/// Render the gauges of all devices and the process counters.
+pub fn render(counters: &Counters, devices: &Devices, calibration_margin: u16) -> String {
+    let mut out = String::new();
+    gauge(&mut out, devices, "smartmail_distance_mm", "Last measured distance",
+          |s| s.last_distance.map(|v| v as f64));
+    gauge(&mut out, devices, "smartmail_mailbox_full", "Whether the mailbox is full (1) or empty (0)",
+          |s| s.is_full(calibration_margin).map(|full| if full { 1.0 } else { 0.0 }));
+    gauge(&mut out, devices, "smartmail_voltage_volts", "Last measured battery voltage",
+          |s| s.last_voltage.map(|v| v as f64));
+    gauge(&mut out, devices, "smartmail_temperature_celsius", "Last measured temperature",
+          |s| s.last_temperature.map(|v| v as f64));
+    gauge(&mut out, devices, "smartmail_rssi_dbm", "RSSI of the best gateway of the last uplink",
+          |s| s.last_rssi.map(|v| v as f64));
+    gauge(&mut out, devices, "smartmail_snr_db", "SNR of the best gateway of the last uplink",
+          |s| s.last_snr.map(|v| v as f64));
+    gauge(&mut out, devices, "smartmail_spreading_factor", "Spreading factor of the last uplink",
+          |s| s.data_rates.last().and_then(|dr| dr.sf()).map(|v| v as f64));
+    gauge(&mut out, devices, "smartmail_last_seen_timestamp_seconds", "Unix timestamp of the last uplink",
+          |s| s.last_seen.map(|v| v as f64));
+
+    let uplinks: Vec<_> = counters.uplinks.iter()
+        .map(|(port, &n)| (Some(("port", port.to_string())), n as f64))
+        .collect();
+    metric(&mut out, "smartmail_uplinks_total", "counter", "Received uplinks per port", &uplinks);
+    metric(&mut out, "smartmail_decode_errors_total", "counter", "Uplinks that could not be decoded",
+           &[(None, counters.decode_errors as f64)]);
+    let sent: Vec<_> = counters.notifications_sent.iter()
+        .map(|(backend, &n)| (Some(("backend", backend.to_string())), n as f64))
+        .collect();
+    metric(&mut out, "smartmail_notifications_sent_total", "counter", "Sent notifications per backend", &sent);
+    let failed: Vec<_> = counters.notifications_failed.iter()
+        .map(|(backend, &n)| (Some(("backend", backend.to_string())), n as f64))
+        .collect();
+    metric(&mut out, "smartmail_notifications_failed_total", "counter", "Failed notification attempts per backend", &failed);
+    metric(&mut out, "smartmail_influxdb_write_failures_total", "counter", "Failed InfluxDB writes",
+           &[(None, counters.influxdb_write_failures as f64)]);
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ttn::DataRate;
+
+    #[test]
+    fn test_render() {
+        let mut devices = Devices::new();
+        {
+            let state = devices.get_mut("0004A30B001A2B3C");
+            state.last_distance = Some(280);
+            state.last_voltage = Some(3.5);
+            state.data_rates.add(DataRate::Lora { sf: 9, bw: 125 });
+        }
+        devices.get_mut("0004A30B001A2B3D").last_distance = Some(400);
+        let mut counters = Counters::new();
+        *counters.uplinks.entry(102).or_insert(0) += 3;
+        *counters.notifications_sent.entry("threema").or_insert(0) += 1;
+
+        let out = render(&counters, &devices, 50);
+        assert!(out.contains("# TYPE smartmail_distance_mm gauge\n\
+                              smartmail_distance_mm{deveui=\"0004A30B001A2B3C\"} 280\n\
+                              smartmail_distance_mm{deveui=\"0004A30B001A2B3D\"} 400\n"));
+        assert!(out.contains("smartmail_mailbox_full{deveui=\"0004A30B001A2B3C\"} 1\n"));
+        assert!(out.contains("smartmail_voltage_volts{deveui=\"0004A30B001A2B3C\"} 3.5\n"));
+        assert!(!out.contains("smartmail_voltage_volts{deveui=\"0004A30B001A2B3D\"}"));
+        assert!(!out.contains("smartmail_temperature_celsius"));
+        assert!(out.contains("smartmail_spreading_factor{deveui=\"0004A30B001A2B3C\"} 9\n"));
+        assert!(out.contains("smartmail_uplinks_total{port=\"102\"} 3\n"));
+        assert!(out.contains("smartmail_notifications_sent_total{backend=\"threema\"} 1\n"));
+        assert!(out.contains("smartmail_decode_errors_total 0\n"));
+        assert_eq!(escape("a\"b\\c"), "a\\\"b\\\\c");
+    }
+}
@@ -0,0 +1,219 @@
+//! A durable outbox for notifications that could not be delivered.
+//!
+//! Failed messages are persisted to a JSON file and retried with exponential
+//! backoff until they are either delivered or exceed the maximum age.
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::{self, Value};
+
+
+/// Delay before the first retry (in seconds).
+static INITIAL_BACKOFF: u64 = 30;
+
+/// Upper bound for the delay between two retries (in seconds).
+static MAX_BACKOFF: u64 = 60 * 60;
+
+/// Return the number of seconds to wait after the given number of failed
+/// delivery attempts.
+pub fn backoff(attempts: u32) -> u64 {
+    let exponent = attempts.saturating_sub(1);
+    if exponent >= 32 {
+        return MAX_BACKOFF;
+    }
+    INITIAL_BACKOFF.saturating_mul(1 << exponent).min(MAX_BACKOFF)
+}
+
+/// A notification waiting to be delivered.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Entry {
+    pub id: u64,
+    pub to: String,
+    pub msg: String,
+    /// Unix timestamp of the first delivery attempt.
+    pub created: u64,
+    pub attempts: u32,
+    /// Unix timestamp of the next delivery attempt.
+    pub next_attempt: u64,
+}
+
+impl Entry {
+    fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "to": self.to,
+            "msg": self.msg,
+            "created": self.created,
+            "attempts": self.attempts,
+            "next_attempt": self.next_attempt,
+        })
+    }
+
+    fn from_json(val: &Value) -> Option<Entry> {
+        Some(Entry {
+            id: val.get("id")?.as_u64()?,
+            to: val.get("to")?.as_str()?.to_owned(),
+            msg: val.get("msg")?.as_str()?.to_owned(),
+            created: val.get("created")?.as_u64()?,
+            attempts: val.get("attempts")?.as_u64()? as u32,
+            next_attempt: val.get("next_attempt")?.as_u64()?,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Outbox {
+    path: Option<PathBuf>,
+    entries: Vec<Entry>,
+    next_id: u64,
+    /// Number of messages that were dropped because they exceeded the
+    /// maximum age.
+    pub failed: u64,
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Load pending entries from the specified file.
+    ///
+    /// The path is remembered, all later changes are written back to it. A
+    /// missing file is not an error.
+    pub fn load(&mut self, path: &Path) -> Result<(), String> {
+        self.path = Some(path.to_owned());
+        let mut contents = String::new();
+        match File::open(path) {
+            Ok(mut file) => {
+                file.read_to_string(&mut contents)
+                    .map_err(|e| format!("Could not read outbox file {}: {}", path.display(), e))?;
+            },
+            Err(_) => return Ok(()),
+        };
+        let decoded: Value = serde_json::from_str(&contents)
+            .map_err(|e| format!("Could not parse outbox file {}: {}", path.display(), e))?;
+        let entries = decoded.as_array()
+            .ok_or_else(|| format!("Outbox file {} does not contain an array", path.display()))?;
+        for val in entries {
+            match Entry::from_json(val) {
+                Some(entry) => self.entries.push(entry),
+                None => warn!("Ignoring invalid outbox entry: {}", val),
+            }
+        }
+        self.next_id = self.entries.iter().map(|e| e.id + 1).max().unwrap_or(0);
+        Ok(())
+    }
+
+    /// Return the number of pending entries.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Queue a message after its first delivery attempt failed.
+    pub fn push(&mut self, to: &str, msg: &str, now: u64) {
+        let entry = Entry {
+            id: self.next_id,
+            to: to.to_owned(),
+            msg: msg.to_owned(),
+            created: now,
+            attempts: 1,
+            next_attempt: now + backoff(1),
+        };
+        self.next_id += 1;
+        self.entries.push(entry);
+        self.persist();
+    }
+
+    /// Return copies of all entries that are due for another attempt.
+    pub fn due(&self, now: u64) -> Vec<Entry> {
+        self.entries.iter().filter(|e| e.next_attempt <= now).cloned().collect()
+    }
+
+    /// Remove an entry after it has been delivered.
+    pub fn delivered(&mut self, id: u64) {
+        self.entries.retain(|e| e.id != id);
+        self.persist();
+    }
+
+    /// Record another failed attempt and schedule the next one.
+    pub fn attempt_failed(&mut self, id: u64, now: u64) {
+        if let Some(entry) = self.entries.iter_mut().find(|e| e.id == id) {
+            entry.attempts += 1;
+            entry.next_attempt = now + backoff(entry.attempts);
+        }
+        self.persist();
+    }
+
+    /// Remove and return all entries that are older than `max_age` seconds.
+    pub fn expire(&mut self, now: u64, max_age: u64) -> Vec<Entry> {
+        let (expired, pending): (Vec<Entry>, Vec<Entry>) = self.entries.drain(..)
+            .partition(|e| now.saturating_sub(e.created) > max_age);
+        self.entries = pending;
+        if !expired.is_empty() {
+            self.failed += expired.len() as u64;
+            self.persist();
+        }
+        expired
+    }
+
+    /// Write all pending entries to disk.
+    ///
+    /// The file is replaced atomically, so a crash never leaves a partially
+    /// written outbox behind.
+    fn persist(&self) {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return,
+        };
+        let json = Value::Array(self.entries.iter().map(|e| e.to_json()).collect());
+        let tmp_path = path.with_extension("tmp");
+        let res = File::create(&tmp_path)
+            .and_then(|mut file| file.write_all(json.to_string().as_bytes()).and_then(|_| file.sync_all()))
+            .and_then(|_| fs::rename(&tmp_path, path));
+        if let Err(e) = res {
+            error!("Could not write outbox file {}: {}", path.display(), e);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), 30);
+        assert_eq!(backoff(2), 60);
+        assert_eq!(backoff(3), 120);
+        assert_eq!(backoff(8), 3600);
+        assert_eq!(backoff(100), 3600);
+    }
+
+    #[test]
+    fn test_retry_and_expire() {
+        let mut outbox = Outbox::new();
+        outbox.push("ECHOECHO", "Mailbox is full!", 1000);
+        outbox.push("ABCDEFGH", "Mailbox is full!", 1010);
+        assert_eq!(outbox.len(), 2);
+        assert!(outbox.due(1020).is_empty());
+
+        let due = outbox.due(1030);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].to, "ECHOECHO");
+
+        outbox.attempt_failed(due[0].id, 1030);
+        assert!(outbox.due(1089).iter().all(|e| e.to != "ECHOECHO"));
+        assert_eq!(outbox.due(1090).len(), 2);
+
+        outbox.delivered(due[0].id);
+        assert_eq!(outbox.len(), 1);
+
+        assert!(outbox.expire(2000, 1000).is_empty());
+        assert_eq!(outbox.expire(2011, 1000).len(), 1);
+        assert_eq!(outbox.len(), 0);
+        assert_eq!(outbox.failed, 1);
+    }
+}
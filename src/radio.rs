@@ -0,0 +1,291 @@
+//! Monitoring of the radio link of the devices.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use ttn::DataRate;
+
+
+/// The TTN fair use policy allows 30 seconds of uplink airtime per device and
+/// day (in nanoseconds, like the airtime in the uplink metadata).
+pub static AIRTIME_BUDGET: u64 = 30 * 1_000_000_000;
+
+/// Length of the rolling airtime window in seconds.
+static AIRTIME_WINDOW: u64 = 86400;
+
+/// Number of recent data rates that are analysed.
+static DATA_RATE_WINDOW: usize = 20;
+
+/// A device is stuck at a high spreading factor if this many consecutive
+/// uplinks used SF11 or SF12.
+static STUCK_UPLINKS: usize = 10;
+
+/// ADR oscillates if the data rate changed at least this many times within
+/// the analysed uplinks.
+static OSCILLATION_CHANGES: usize = 6;
+
+/// Thresholds below which the link quality of an uplink is considered poor.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct CoverageThresholds {
+    /// Minimum RSSI in dBm of the best gateway.
+    pub min_rssi: f32,
+    /// Minimum SNR in dB of the best gateway.
+    pub min_snr: f32,
+    /// Number of consecutive uplinks after which the coverage changes from
+    /// good to poor or vice versa.
+    pub uplinks: u32,
+}
+
+impl Default for CoverageThresholds {
+    fn default() -> Self {
+        CoverageThresholds {
+            min_rssi: -120.0,
+            min_snr: -15.0,
+            uplinks: 5,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Coverage {
+    poor: bool,
+    /// Number of consecutive uplinks whose link quality does not match the
+    /// current state.
+    streak: u32,
+}
+
+impl Coverage {
+    pub fn is_poor(&self) -> bool {
+        self.poor
+    }
+
+    /// Record the link quality of the best gateway of an uplink.
+    ///
+    /// Return the new state (`true` if the coverage is poor) if it changed.
+    pub fn update(&mut self, thresholds: &CoverageThresholds, rssi: f32, snr: f32) -> Option<bool> {
+        let poor = rssi < thresholds.min_rssi || snr < thresholds.min_snr;
+        if poor == self.poor {
+            self.streak = 0;
+            return None;
+        }
+        self.streak += 1;
+        if self.streak < thresholds.uplinks {
+            return None;
+        }
+        self.poor = poor;
+        self.streak = 0;
+        Some(poor)
+    }
+}
+
+/// The uplink airtime of a device in a rolling 24h window.
+#[derive(Debug, Default, Clone)]
+pub struct Airtime {
+    /// Timestamps and airtimes (in nanoseconds) of the uplinks.
+    uplinks: VecDeque<(u64, u64)>,
+    /// Unix timestamp of the last warning.
+    warned: Option<u64>,
+}
+
+impl Airtime {
+    pub fn add(&mut self, now: u64, airtime: u64) {
+        self.uplinks.push_back((now, airtime));
+        while self.uplinks.front().map_or(false, |&(t, _)| now.saturating_sub(t) >= AIRTIME_WINDOW) {
+            self.uplinks.pop_front();
+        }
+    }
+
+    /// Return the airtime (in nanoseconds) used in the last 24 hours.
+    pub fn used(&self) -> u64 {
+        self.uplinks.iter().map(|&(_, airtime)| airtime).sum()
+    }
+
+    /// Return the average number of seconds between the uplinks in the last
+    /// 24 hours.
+    pub fn interval(&self) -> Option<u64> {
+        match (self.uplinks.front(), self.uplinks.back()) {
+            (Some(&(first, _)), Some(&(last, _))) if self.uplinks.len() > 1 =>
+                Some((last - first) / (self.uplinks.len() as u64 - 1)),
+            _ => None,
+        }
+    }
+
+    /// Return the airtime (in nanoseconds) that the device would use per day
+    /// if it kept sending uplinks like the last one at the average interval.
+    pub fn projected(&self) -> Option<u64> {
+        let interval = self.interval()?;
+        let &(_, airtime) = self.uplinks.back()?;
+        Some(airtime * AIRTIME_WINDOW / interval.max(1))
+    }
+
+    /// Return whether the used airtime reached `warning` (in nanoseconds) and
+    /// no warning was sent in the last 24 hours.
+    pub fn check(&self, now: u64, warning: u64) -> bool {
+        let recently_warned = self.warned.map_or(false, |t| now.saturating_sub(t) < AIRTIME_WINDOW);
+        self.used() >= warning && !recently_warned
+    }
+
+    /// Remember that a warning was sent.
+    pub fn warned(&mut self, now: u64) {
+        self.warned = Some(now);
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum AdrIssue {
+    /// The device uses SF11 or SF12 all the time, which drains the battery.
+    StuckAtHighSf(u8),
+    /// The data rate changed the specified number of times within the last
+    /// uplinks.
+    Oscillating(usize),
+}
+
+/// The data rates of the uplinks of a device.
+#[derive(Debug, Default, Clone)]
+pub struct DataRateHistory {
+    recent: VecDeque<DataRate>,
+    /// Number of uplinks per data rate since startup.
+    counts: BTreeMap<DataRate, u64>,
+    stuck: bool,
+    oscillating: bool,
+}
+
+impl DataRateHistory {
+    /// Add the data rate of an uplink, and return the issues that were
+    /// detected with this uplink.
+    ///
+    /// An issue is only reported again after it disappeared.
+    pub fn add(&mut self, data_rate: DataRate) -> Vec<AdrIssue> {
+        *self.counts.entry(data_rate.clone()).or_insert(0) += 1;
+        self.recent.push_back(data_rate);
+        while self.recent.len() > DATA_RATE_WINDOW {
+            self.recent.pop_front();
+        }
+
+        let mut issues = vec![];
+
+        let high_sf = self.recent.iter().rev().take(STUCK_UPLINKS)
+            .filter(|dr| dr.sf().map_or(false, |sf| sf >= 11))
+            .count();
+        let stuck = high_sf == STUCK_UPLINKS;
+        if stuck && !self.stuck {
+            let sf = self.recent.back().and_then(|dr| dr.sf()).unwrap_or(0);
+            issues.push(AdrIssue::StuckAtHighSf(sf));
+        }
+        self.stuck = stuck;
+
+        let changes = self.changes();
+        if changes >= OSCILLATION_CHANGES && !self.oscillating {
+            self.oscillating = true;
+            issues.push(AdrIssue::Oscillating(changes));
+        } else if changes < OSCILLATION_CHANGES / 2 {
+            self.oscillating = false;
+        }
+
+        issues
+    }
+
+    /// Return the number of data rate changes within the recent uplinks.
+    pub fn changes(&self) -> usize {
+        self.recent.iter().zip(self.recent.iter().skip(1)).filter(|&(a, b)| a != b).count()
+    }
+
+    /// Return the data rate of the last uplink.
+    pub fn last(&self) -> Option<&DataRate> {
+        self.recent.back()
+    }
+
+    pub fn is_stuck(&self) -> bool {
+        self.stuck
+    }
+
+    pub fn is_oscillating(&self) -> bool {
+        self.oscillating
+    }
+
+    /// Return the number of uplinks per data rate since startup.
+    pub fn distribution<'a>(&'a self) -> ::std::collections::btree_map::Iter<'a, DataRate, u64> {
+        self.counts.iter()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coverage() {
+        let thresholds = CoverageThresholds { min_rssi: -120.0, min_snr: -15.0, uplinks: 3 };
+        let mut coverage = Coverage::default();
+        assert_eq!(coverage.update(&thresholds, -121.0, 5.0), None);
+        assert_eq!(coverage.update(&thresholds, -110.0, -16.0), None);
+        assert_eq!(coverage.update(&thresholds, -100.0, 5.0), None);
+        assert_eq!(coverage.update(&thresholds, -121.0, 5.0), None);
+        assert_eq!(coverage.update(&thresholds, -121.0, 5.0), None);
+        assert_eq!(coverage.update(&thresholds, -121.0, 5.0), Some(true));
+        assert!(coverage.is_poor());
+        assert_eq!(coverage.update(&thresholds, -121.0, 5.0), None);
+        assert_eq!(coverage.update(&thresholds, -100.0, 5.0), None);
+        assert_eq!(coverage.update(&thresholds, -100.0, 5.0), None);
+        assert_eq!(coverage.update(&thresholds, -100.0, 5.0), Some(false));
+    }
+
+    #[test]
+    fn test_data_rate_stuck() {
+        let sf = |sf| DataRate::Lora { sf: sf, bw: 125 };
+        let mut history = DataRateHistory::default();
+        assert_eq!(history.add(sf(7)), vec![]);
+        for _ in 0..9 {
+            assert_eq!(history.add(sf(12)), vec![]);
+        }
+        assert_eq!(history.add(sf(12)), vec![AdrIssue::StuckAtHighSf(12)]);
+        assert_eq!(history.add(sf(11)), vec![]);
+        assert!(history.is_stuck());
+        assert_eq!(history.add(sf(9)), vec![]);
+        assert!(!history.is_stuck());
+        assert_eq!(history.distribution().map(|(dr, &n)| (dr.to_string(), n)).collect::<Vec<_>>(),
+                   vec![("SF7BW125".to_owned(), 1), ("SF9BW125".to_owned(), 1),
+                        ("SF11BW125".to_owned(), 1), ("SF12BW125".to_owned(), 10)]);
+    }
+
+    #[test]
+    fn test_data_rate_oscillation() {
+        let sf = |sf| DataRate::Lora { sf: sf, bw: 125 };
+        let mut history = DataRateHistory::default();
+        for _ in 0..5 {
+            assert_eq!(history.add(sf(7)), vec![]);
+        }
+        for i in 0..5 {
+            assert_eq!(history.add(sf(if i % 2 == 0 { 10 } else { 7 })), vec![]);
+        }
+        assert_eq!(history.add(sf(7)), vec![AdrIssue::Oscillating(6)]);
+        assert_eq!(history.add(sf(10)), vec![]);
+        assert!(history.is_oscillating());
+    }
+
+    #[test]
+    fn test_airtime() {
+        let ms = 1_000_000;
+        let mut airtime = Airtime::default();
+        assert_eq!(airtime.projected(), None);
+
+        // An uplink with 1.5s airtime (SF12) every 30 minutes
+        for i in 0..15 {
+            airtime.add(i * 1800, 1500 * ms);
+            assert!(!airtime.check(i * 1800, 24 * 1000 * ms));
+        }
+        assert_eq!(airtime.used(), 22500 * ms);
+        assert_eq!(airtime.interval(), Some(1800));
+        assert_eq!(airtime.projected(), Some(72 * 1000 * ms));
+
+        airtime.add(15 * 1800, 1500 * ms);
+        assert!(airtime.check(15 * 1800, 24 * 1000 * ms));
+        assert!(airtime.check(16 * 1800, 24 * 1000 * ms));
+        airtime.warned(16 * 1800);
+        assert!(!airtime.check(17 * 1800, 24 * 1000 * ms));
+
+        // Uplinks older than 24 hours are dropped
+        airtime.add(86400 + 1800, 1500 * ms);
+        assert_eq!(airtime.used(), 22500 * ms);
+    }
+}
@@ -0,0 +1,104 @@
+//! Per-device, per-recipient rate limiting and deduplication of
+//! notifications.
+
+use std::collections::{HashMap, VecDeque};
+
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Decision {
+    Allow,
+    /// The recipient already got too many notifications for this device in
+    /// the last hour.
+    RateLimited,
+    /// The recipient already got a notification about the same event for this
+    /// device within the dedup window.
+    Duplicate,
+}
+
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    /// Maximum number of notifications per device and recipient per hour, or
+    /// 0 for no limit.
+    max_per_hour: usize,
+    /// Seconds during which identical events are suppressed, or 0 to disable
+    /// deduplication.
+    dedup_window: u64,
+    /// Timestamps and events of the notifications that were sent in the last
+    /// hour (or dedup window), per device and recipient.
+    sent: HashMap<(String, String), VecDeque<(u64, String)>>,
+    /// Number of suppressed notifications.
+    pub suppressed: u64,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn configure(&mut self, max_per_hour: usize, dedup_window: u64) {
+        self.max_per_hour = max_per_hour;
+        self.dedup_window = dedup_window;
+    }
+
+    /// Decide whether a notification about `event` may be sent, and remember
+    /// it if so.
+    ///
+    /// `event` identifies identical notifications, e.g. the template name, so
+    /// that an alert and the following recovery are not duplicates.
+    pub fn check(&mut self, deveui: &str, recipient: &str, event: &str, now: u64) -> Decision {
+        let max_per_hour = self.max_per_hour;
+        let dedup_window = self.dedup_window;
+        let keep = dedup_window.max(3600);
+        let sent = self.sent.entry((deveui.to_owned(), recipient.to_owned())).or_insert_with(VecDeque::new);
+        while sent.front().map_or(false, |&(timestamp, _)| now.saturating_sub(timestamp) >= keep) {
+            sent.pop_front();
+        }
+
+        let decision = if dedup_window > 0 && sent.iter()
+                .any(|&(timestamp, ref e)| e == event && now.saturating_sub(timestamp) < dedup_window) {
+            Decision::Duplicate
+        } else if max_per_hour > 0 && sent.iter()
+                .filter(|&&(timestamp, _)| now.saturating_sub(timestamp) < 3600)
+                .count() >= max_per_hour {
+            Decision::RateLimited
+        } else {
+            Decision::Allow
+        };
+
+        match decision {
+            Decision::Allow => sent.push_back((now, event.to_owned())),
+            _ => self.suppressed += 1,
+        };
+        decision
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit() {
+        let mut limiter = RateLimiter::new();
+        limiter.configure(2, 0);
+        assert_eq!(limiter.check("dev", "ECHOECHO", "full", 0), Decision::Allow);
+        assert_eq!(limiter.check("dev", "ECHOECHO", "empty", 10), Decision::Allow);
+        assert_eq!(limiter.check("dev", "ECHOECHO", "full", 20), Decision::RateLimited);
+        assert_eq!(limiter.check("dev", "ABCDEFGH", "full", 20), Decision::Allow);
+        assert_eq!(limiter.check("other", "ECHOECHO", "full", 20), Decision::Allow);
+        assert_eq!(limiter.check("dev", "ECHOECHO", "full", 3600), Decision::Allow);
+        assert_eq!(limiter.suppressed, 1);
+    }
+
+    #[test]
+    fn test_dedup() {
+        let mut limiter = RateLimiter::new();
+        limiter.configure(0, 600);
+        assert_eq!(limiter.check("dev", "ECHOECHO", "full", 0), Decision::Allow);
+        assert_eq!(limiter.check("dev", "ECHOECHO", "empty", 100), Decision::Allow);
+        assert_eq!(limiter.check("dev", "ECHOECHO", "full", 200), Decision::Duplicate);
+        assert_eq!(limiter.check("dev", "ECHOECHO", "full", 600), Decision::Allow);
+        assert_eq!(limiter.suppressed, 1);
+    }
+}
@@ -0,0 +1,117 @@
+//! Tracking of sent Threema messages and their delivery receipts.
+
+use std::collections::HashMap;
+use std::fmt;
+
+
+/// Sent messages are forgotten after this many seconds.
+static MAX_AGE: u64 = 7 * 24 * 3600;
+
+/// The delivery status of a sent message.
+///
+/// The variants are ordered, so a later receipt never downgrades the status
+/// (e.g. a "received" receipt that arrives after the "read" receipt).
+#[derive(Debug, PartialEq, PartialOrd, Copy, Clone)]
+pub enum DeliveryStatus {
+    Sent,
+    Received,
+    Read,
+    Acknowledged,
+    Declined,
+}
+
+impl DeliveryStatus {
+    /// Parse the status byte of a Threema delivery receipt.
+    pub fn from_receipt(val: u8) -> Option<DeliveryStatus> {
+        match val {
+            1 => Some(DeliveryStatus::Received),
+            2 => Some(DeliveryStatus::Read),
+            3 => Some(DeliveryStatus::Acknowledged),
+            4 => Some(DeliveryStatus::Declined),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DeliveryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            DeliveryStatus::Sent => "sent",
+            DeliveryStatus::Received => "received",
+            DeliveryStatus::Read => "read",
+            DeliveryStatus::Acknowledged => "acknowledged",
+            DeliveryStatus::Declined => "declined",
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct SentMessage {
+    pub to: String,
+    pub msg: String,
+    /// Unix timestamp when the message was handed to the gateway.
+    pub sent: u64,
+    pub status: DeliveryStatus,
+    /// Unix timestamp of the last status change.
+    pub updated: u64,
+}
+
+/// All messages sent in the last days, indexed by their Threema message ID.
+#[derive(Debug, Default)]
+pub struct DeliveryLog {
+    messages: HashMap<String, SentMessage>,
+}
+
+impl DeliveryLog {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Remember a message that was just sent.
+    pub fn sent(&mut self, msg_id: &str, to: &str, msg: &str, now: u64) {
+        self.messages.retain(|_, m| now.saturating_sub(m.sent) <= MAX_AGE);
+        self.messages.insert(msg_id.to_lowercase(), SentMessage {
+            to: to.to_owned(),
+            msg: msg.to_owned(),
+            sent: now,
+            status: DeliveryStatus::Sent,
+            updated: now,
+        });
+    }
+
+    /// Record a delivery receipt.
+    ///
+    /// Return the updated message, or `None` if the message ID is unknown.
+    pub fn receipt(&mut self, msg_id: &str, status: DeliveryStatus, now: u64) -> Option<&SentMessage> {
+        let msg = self.messages.get_mut(&msg_id.to_lowercase())?;
+        if status > msg.status {
+            msg.status = status;
+            msg.updated = now;
+        }
+        Some(msg)
+    }
+
+    #[cfg(test)]
+    pub fn get(&self, msg_id: &str) -> Option<&SentMessage> {
+        self.messages.get(&msg_id.to_lowercase())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_receipts() {
+        let mut log = DeliveryLog::new();
+        log.sent("0123456789abcdef", "ECHOECHO", "Mailbox is full!", 1000);
+        assert!(log.receipt("fedcba9876543210", DeliveryStatus::Read, 1001).is_none());
+
+        log.receipt("0123456789ABCDEF", DeliveryStatus::Read, 1010);
+        log.receipt("0123456789abcdef", DeliveryStatus::Received, 1020);
+        let msg = log.get("0123456789abcdef").unwrap();
+        assert_eq!(msg.status, DeliveryStatus::Read);
+        assert_eq!(msg.updated, 1010);
+    }
+}
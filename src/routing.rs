@@ -0,0 +1,215 @@
+//! Routing of events to recipient groups.
+
+use std::fmt;
+use std::str::FromStr;
+
+
+/// The events that recipients can subscribe to.
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
+pub enum EventType {
+    Full,
+    Empty,
+    PickedUp,
+    LowBattery,
+    DeviceOffline,
+    DecodeError,
+    Temperature,
+    Joined,
+    Coverage,
+    Airtime,
+    DataRate,
+    Digest,
+}
+
+pub static EVENT_TYPES: &'static [EventType] = &[
+    EventType::Full,
+    EventType::Empty,
+    EventType::PickedUp,
+    EventType::LowBattery,
+    EventType::DeviceOffline,
+    EventType::DecodeError,
+    EventType::Temperature,
+    EventType::Joined,
+    EventType::Coverage,
+    EventType::Airtime,
+    EventType::DataRate,
+    EventType::Digest,
+];
+
+/// The events that recipients without explicit subscriptions get.
+pub static DEFAULT_EVENTS: &'static [EventType] = &[EventType::Full, EventType::Empty, EventType::PickedUp];
+
+impl EventType {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            EventType::Full => "full",
+            EventType::Empty => "empty",
+            EventType::PickedUp => "picked_up",
+            EventType::LowBattery => "low_battery",
+            EventType::DeviceOffline => "device_offline",
+            EventType::DecodeError => "decode_error",
+            EventType::Temperature => "temperature",
+            EventType::Joined => "joined",
+            EventType::Coverage => "coverage",
+            EventType::Airtime => "airtime",
+            EventType::DataRate => "data_rate",
+            EventType::Digest => "digest",
+        }
+    }
+}
+
+impl fmt::Display for EventType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for EventType {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        EVENT_TYPES.iter()
+            .find(|event| event.name() == val.trim())
+            .cloned()
+            .ok_or_else(|| format!("Unknown event: {}", val))
+    }
+}
+
+/// The notifier backends.
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
+pub enum Backend {
+    /// Send an end-to-end encrypted Threema message.
+    Threema,
+    /// Only write the notification to the log.
+    Log,
+}
+
+impl Backend {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Backend::Threema => "threema",
+            Backend::Log => "log",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct Recipient {
+    pub backend: Backend,
+    /// The backend specific address, e.g. the Threema ID.
+    pub address: String,
+}
+
+impl Recipient {
+    pub fn threema(id: &str) -> Self {
+        Recipient {
+            backend: Backend::Threema,
+            address: id.to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.backend {
+            Backend::Threema => write!(f, "{}", self.address),
+            Backend::Log => write!(f, "log:{}", self.address),
+        }
+    }
+}
+
+/// Parse a recipient in the format `[backend:]address`. Recipients without
+/// backend are Threema IDs.
+impl FromStr for Recipient {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let val = val.trim();
+        let mut parts = val.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(id), None) => Ok(Recipient::threema(id)),
+            (Some("threema"), Some(id)) => Ok(Recipient::threema(id)),
+            (Some("log"), Some(name)) => Ok(Recipient { backend: Backend::Log, address: name.to_owned() }),
+            (Some(backend), Some(_)) => Err(format!("Unknown notifier backend: {}", backend)),
+            (None, _) => Err(format!("Invalid recipient: {}", val)),
+        }
+    }
+}
+
+/// A group of recipients with the same subscriptions.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Group {
+    pub name: String,
+    pub members: Vec<Recipient>,
+    pub events: Vec<EventType>,
+}
+
+impl Group {
+    /// Parse a group from its comma separated members and events. The event
+    /// `*` subscribes to all events.
+    pub fn parse(name: &str, members: &str, events: &str) -> Result<Group, String> {
+        let members = members.split(',')
+            .filter(|m| !m.trim().is_empty())
+            .map(|m| m.parse())
+            .collect::<Result<Vec<Recipient>, String>>()?;
+        let events = if events.trim() == "*" {
+            EVENT_TYPES.to_vec()
+        } else {
+            events.split(',')
+                .filter(|e| !e.trim().is_empty())
+                .map(|e| e.parse())
+                .collect::<Result<Vec<EventType>, String>>()?
+        };
+        Ok(Group {
+            name: name.to_owned(),
+            members: members,
+            events: events,
+        })
+    }
+}
+
+/// Return all recipients that subscribed to the event, without duplicates.
+pub fn recipients_for(groups: &[Group], event: EventType) -> Vec<Recipient> {
+    let mut recipients: Vec<Recipient> = vec![];
+    for group in groups.iter().filter(|g| g.events.contains(&event)) {
+        for member in group.members.iter() {
+            if !recipients.contains(member) {
+                recipients.push(member.clone());
+            }
+        }
+    }
+    recipients
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_recipient() {
+        assert_eq!("ECHOECHO".parse(), Ok(Recipient::threema("ECHOECHO")));
+        assert_eq!("threema:ECHOECHO".parse(), Ok(Recipient::threema("ECHOECHO")));
+        assert_eq!("log:".parse(), Ok(Recipient { backend: Backend::Log, address: "".into() }));
+        assert!("email:foo@example.com".parse::<Recipient>().is_err());
+    }
+
+    #[test]
+    fn test_routing() {
+        let groups = vec![
+            Group::parse("family", "ECHOECHO,ABCDEFGH", "full").unwrap(),
+            Group::parse("admins", "ECHOECHO", "full,empty,low_battery").unwrap(),
+            Group::parse("log", "log:", "*").unwrap(),
+        ];
+        assert_eq!(
+            recipients_for(&groups, EventType::Full),
+            vec!["ECHOECHO".parse().unwrap(), "ABCDEFGH".parse().unwrap(), "log:".parse().unwrap()]
+        );
+        assert_eq!(
+            recipients_for(&groups, EventType::LowBattery),
+            vec!["ECHOECHO".parse().unwrap(), "log:".parse().unwrap()]
+        );
+        assert_eq!(recipients_for(&groups, EventType::DecodeError), vec!["log:".parse().unwrap()]);
+        assert!(Group::parse("broken", "ECHOECHO", "full,everything").is_err());
+    }
+}
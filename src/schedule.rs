@@ -0,0 +1,214 @@
+//! Per-recipient quiet hours.
+//!
+//! A schedule is a `;` separated list of quiet windows in the format
+//! `<recipient> <days> <start>-<end>`, e.g. `ECHOECHO mon-fri 22:00-07:00`.
+//! The recipient `*` matches everybody. Days are either `*`, a single day
+//! (`sat`), a range (`mon-fri`) or a comma separated list (`sat,sun`).
+//! Windows that end before they start continue on the next day.
+
+use std::str::FromStr;
+
+use chrono::{Datelike, Duration, NaiveDateTime, Timelike};
+
+
+/// What happens to notifications during quiet hours.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum QuietAction {
+    /// Drop the notification.
+    Suppress,
+    /// Send the notification when the quiet hours end.
+    Delay,
+    /// Send a single summary of all notifications when the quiet hours end.
+    Summarise,
+}
+
+impl FromStr for QuietAction {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        match val.trim() {
+            "suppress" => Ok(QuietAction::Suppress),
+            "delay" => Ok(QuietAction::Delay),
+            "summarise" | "summarize" => Ok(QuietAction::Summarise),
+            _ => Err(format!("Invalid quiet hours action: {}", val)),
+        }
+    }
+}
+
+static DAYS: &'static [&'static str] = &["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+pub fn parse_day(val: &str) -> Result<usize, String> {
+    DAYS.iter().position(|day| *day == val.to_lowercase())
+        .ok_or_else(|| format!("Invalid weekday: {}", val))
+}
+
+/// Parse a time like `07:30` into minutes since midnight.
+pub fn parse_time(val: &str) -> Result<u32, String> {
+    let mut parts = val.splitn(2, ':');
+    let hours: Option<u32> = parts.next().and_then(|h| h.parse().ok());
+    let minutes: Option<u32> = parts.next().and_then(|m| m.parse().ok());
+    match (hours, minutes) {
+        (Some(h), Some(m)) if m < 60 && h * 60 + m <= 24 * 60 => Ok(h * 60 + m),
+        _ => Err(format!("Invalid time: {}", val)),
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+struct QuietWindow {
+    recipient: String,
+    /// The days (starting with monday) on which the window starts.
+    days: [bool; 7],
+    /// Minutes since midnight.
+    start: u32,
+    end: u32,
+}
+
+impl QuietWindow {
+    fn applies_to(&self, recipient: &str) -> bool {
+        self.recipient == "*" || self.recipient == recipient
+    }
+
+    fn contains(&self, time: NaiveDateTime) -> bool {
+        let minutes = time.hour() * 60 + time.minute();
+        let today = time.weekday().num_days_from_monday() as usize;
+        let yesterday = (today + 6) % 7;
+        if self.start < self.end {
+            self.days[today] && minutes >= self.start && minutes < self.end
+        } else {
+            (self.days[today] && minutes >= self.start) || (self.days[yesterday] && minutes < self.end)
+        }
+    }
+}
+
+impl FromStr for QuietWindow {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = val.split_whitespace().collect();
+        if parts.len() != 3 {
+            return Err(format!("Invalid quiet hours (expected \"<recipient> <days> <start>-<end>\"): {}", val));
+        }
+
+        let mut days = [false; 7];
+        for spec in parts[1].split(',') {
+            if spec == "*" {
+                days = [true; 7];
+                continue;
+            }
+            let mut range = spec.splitn(2, '-');
+            let first = parse_day(range.next().unwrap_or(""))?;
+            let last = match range.next() {
+                Some(day) => parse_day(day)?,
+                None => first,
+            };
+            let mut day = first;
+            loop {
+                days[day] = true;
+                if day == last {
+                    break;
+                }
+                day = (day + 1) % 7;
+            }
+        }
+
+        let mut times = parts[2].splitn(2, '-');
+        let start = parse_time(times.next().unwrap_or(""))?;
+        let end = parse_time(times.next().unwrap_or(""))?;
+
+        Ok(QuietWindow {
+            recipient: parts[0].to_owned(),
+            days: days,
+            start: start,
+            end: end,
+        })
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Schedule {
+    windows: Vec<QuietWindow>,
+}
+
+impl Schedule {
+    /// Return whether the recipient is in quiet hours at the specified
+    /// (local) time.
+    pub fn is_quiet(&self, recipient: &str, time: NaiveDateTime) -> bool {
+        self.windows.iter().any(|w| w.applies_to(recipient) && w.contains(time))
+    }
+
+    /// Return the end of the quiet hours that contain the specified time, or
+    /// `None` if the recipient is not in quiet hours or the quiet hours do
+    /// not end within the next week.
+    pub fn quiet_until(&self, recipient: &str, time: NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut end = time;
+        for _ in 0..(7 * 24 * 60) {
+            if !self.is_quiet(recipient, end) {
+                return if end == time { None } else { Some(end) };
+            }
+            end = end + Duration::minutes(1);
+        }
+        None
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let windows = val.split(';')
+            .filter(|window| !window.trim().is_empty())
+            .map(|window| window.parse())
+            .collect::<Result<Vec<QuietWindow>, String>>()?;
+        Ok(Schedule { windows: windows })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::NaiveDate;
+
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        // 2018-01-01 was a monday
+        NaiveDate::from_ymd_opt(2018, 1, day).unwrap().and_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!("ECHOECHO mon-fri 22:00-07:00; * sat,sun 00:00-24:00".parse::<Schedule>().is_ok());
+        assert!("ECHOECHO mon-fri".parse::<Schedule>().is_err());
+        assert!("ECHOECHO foo 22:00-07:00".parse::<Schedule>().is_err());
+        assert!("ECHOECHO * 25:00-07:00".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn test_is_quiet() {
+        let schedule: Schedule = "ECHOECHO fri-sun 22:00-07:00; * sun 00:00-10:00".parse().unwrap();
+
+        // Friday night until saturday morning
+        assert!(!schedule.is_quiet("ECHOECHO", at(5, 21, 59)));
+        assert!(schedule.is_quiet("ECHOECHO", at(5, 22, 0)));
+        assert!(schedule.is_quiet("ECHOECHO", at(6, 6, 59)));
+        assert!(!schedule.is_quiet("ECHOECHO", at(6, 7, 0)));
+
+        // Sunday night until monday morning
+        assert!(schedule.is_quiet("ECHOECHO", at(8, 6, 0)));
+        assert!(!schedule.is_quiet("ECHOECHO", at(9, 6, 0)));
+
+        // Thursday night is not quiet
+        assert!(!schedule.is_quiet("ECHOECHO", at(4, 23, 0)));
+
+        // Sunday morning is quiet for everybody
+        assert!(schedule.is_quiet("ABCDEFGH", at(7, 9, 0)));
+        assert!(!schedule.is_quiet("ABCDEFGH", at(7, 10, 0)));
+    }
+
+    #[test]
+    fn test_quiet_until() {
+        let schedule: Schedule = "ECHOECHO sat 22:00-07:00; ECHOECHO sun 00:00-10:00".parse().unwrap();
+        assert_eq!(schedule.quiet_until("ECHOECHO", at(6, 23, 0)), Some(at(7, 10, 0)));
+        assert_eq!(schedule.quiet_until("ECHOECHO", at(6, 12, 0)), None);
+    }
+}
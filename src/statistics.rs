@@ -0,0 +1,256 @@
+//! Mail delivery statistics and the schedule of the digest.
+//!
+//! The statistics are computed from the `full` and `empty` transitions in the
+//! event log: every change to `full` is a delivery, the following change to
+//! `empty` is the pickup.
+
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use chrono::{Datelike, Duration, NaiveDateTime, TimeZone, Timelike};
+
+use eventlog::{Entry, Record};
+use schedule::{parse_day, parse_time};
+
+
+static WEEKDAYS: &'static [&'static str] = &["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+/// Seconds per day.
+static DAY: u64 = 24 * 3600;
+
+/// Delivery statistics of a device within a period.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Statistics {
+    /// Number of days (24 hours, counted back from the end) in the period.
+    pub days: u32,
+    pub deliveries: u32,
+    /// Local time of each delivery, in seconds since midnight.
+    pub arrivals: Vec<u32>,
+    /// Seconds between each delivery and the pickup, if the mail was picked
+    /// up already.
+    pub waits: Vec<u64>,
+    /// Number of deliveries per weekday, starting with monday.
+    pub weekdays: [u32; 7],
+    /// Number of days without a delivery, with the days counted back from
+    /// the end of the period.
+    pub days_without_mail: u32,
+}
+
+impl Statistics {
+    /// Compute the statistics of the deliveries between `since` and `until`
+    /// (Unix timestamps).
+    ///
+    /// `entries` are the transitions of a single device, oldest first. They
+    /// may extend beyond `until` to find the pickups of the last deliveries.
+    pub fn compute<Tz: TimeZone>(entries: &[&Entry], since: u64, until: u64, tz: &Tz) -> Statistics {
+        let local = |timestamp: u64| tz.timestamp(timestamp as i64, 0).naive_local();
+        let mut stats = Statistics::default();
+        stats.days = ((until.saturating_sub(since) + DAY - 1) / DAY).max(1) as u32;
+
+        let mut days_with_mail: HashSet<u64> = HashSet::new();
+        let mut delivered: Option<u64> = None;
+        for entry in entries {
+            let state = match entry.record {
+                Record::Transition { ref state } => state.as_str(),
+                _ => continue,
+            };
+            match state {
+                "full" => delivered = Some(entry.timestamp),
+                "empty" => if let Some(delivered) = delivered.take() {
+                    if delivered >= since && delivered <= until {
+                        stats.waits.push(entry.timestamp - delivered);
+                    }
+                },
+                _ => continue,
+            };
+            if state == "full" && entry.timestamp >= since && entry.timestamp <= until {
+                let time = local(entry.timestamp);
+                stats.deliveries += 1;
+                stats.arrivals.push(time.num_seconds_from_midnight());
+                stats.weekdays[time.weekday().num_days_from_monday() as usize] += 1;
+                days_with_mail.insert((until - entry.timestamp) / DAY);
+            }
+        }
+        stats.days_without_mail = stats.days.saturating_sub(days_with_mail.len() as u32);
+        stats
+    }
+
+    /// Return the median arrival time in seconds since midnight.
+    pub fn median_arrival(&self) -> Option<u32> {
+        let mut arrivals = self.arrivals.clone();
+        arrivals.sort();
+        let n = arrivals.len();
+        match n {
+            0 => None,
+            _ if n % 2 == 1 => Some(arrivals[n / 2]),
+            _ => Some((arrivals[n / 2 - 1] + arrivals[n / 2]) / 2),
+        }
+    }
+
+    /// Return the average time (in seconds) until the mail was picked up.
+    pub fn average_wait(&self) -> Option<u64> {
+        if self.waits.is_empty() {
+            return None;
+        }
+        Some(self.waits.iter().sum::<u64>() / self.waits.len() as u64)
+    }
+
+    /// Return the deliveries per weekday, e.g. `Mon 2, Tue 0, …`.
+    pub fn format_weekdays(&self) -> String {
+        WEEKDAYS.iter().zip(self.weekdays.iter())
+            .map(|(day, n)| format!("{} {}", day, n))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Format seconds since midnight like `10:42`.
+pub fn format_time(secs: u32) -> String {
+    format!("{:02}:{:02}", secs / 3600, secs % 3600 / 60)
+}
+
+/// Format a duration like `3h`, `45min` or `1h 30min`.
+pub fn format_wait(secs: u64) -> String {
+    let (hours, minutes) = (secs / 3600, secs % 3600 / 60);
+    match (hours, minutes) {
+        (0, m) => format!("{}min", m),
+        (h, 0) => format!("{}h", h),
+        (h, m) => format!("{}h {}min", h, m),
+    }
+}
+
+/// When the digest is sent, in the format `daily <time>` or `<day> <time>`
+/// for a weekly digest, e.g. `sun 18:00`.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum DigestSchedule {
+    /// Minutes since midnight.
+    Daily(u32),
+    /// The weekday (starting with monday) and minutes since midnight.
+    Weekly(usize, u32),
+}
+
+impl DigestSchedule {
+    /// Return the number of days covered by a digest. The period ends at the
+    /// scheduled time.
+    pub fn days(&self) -> u32 {
+        match *self {
+            DigestSchedule::Daily(_) => 1,
+            DigestSchedule::Weekly(_, _) => 7,
+        }
+    }
+
+    /// Return whether a digest is due after `prev` and up to `now` (local
+    /// times).
+    pub fn is_due(&self, prev: NaiveDateTime, now: NaiveDateTime) -> bool {
+        let (day, minutes) = match *self {
+            DigestSchedule::Daily(minutes) => (None, minutes),
+            DigestSchedule::Weekly(day, minutes) => (Some(day), minutes),
+        };
+        [now.date() - Duration::days(1), now.date()].iter()
+            .filter(|date| day.map_or(true, |day| date.weekday().num_days_from_monday() as usize == day))
+            .map(|date| date.and_hms(0, 0, 0) + Duration::minutes(minutes as i64))
+            .any(|time| time > prev && time <= now)
+    }
+}
+
+impl FromStr for DigestSchedule {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = val.split_whitespace().collect();
+        if parts.len() != 2 {
+            return Err(format!("Invalid digest schedule (expected \"daily <time>\" or \"<day> <time>\"): {}", val));
+        }
+        let minutes = parse_time(parts[1])?;
+        match parts[0].to_lowercase().as_str() {
+            "daily" => Ok(DigestSchedule::Daily(minutes)),
+            day => Ok(DigestSchedule::Weekly(parse_day(day)?, minutes)),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::Utc;
+
+    fn transition(timestamp: u64, state: &str) -> Entry {
+        Entry {
+            timestamp: timestamp,
+            deveui: Some("0004A30B001A2B3C".into()),
+            record: Record::Transition { state: state.to_owned() },
+        }
+    }
+
+    #[test]
+    fn test_compute() {
+        // 2018-01-01 was a monday
+        let monday = 1514764800;
+        let entries = vec![
+            transition(monday - 3600, "full"),
+            transition(monday + 8 * 3600, "empty"),
+            transition(monday + 10 * 3600 + 42 * 60, "full"),
+            transition(monday + 18 * 3600 + 42 * 60, "empty"),
+            transition(monday + 86400 + 11 * 3600, "offline"),
+            transition(monday + 2 * 86400 + 9 * 3600, "full"),
+            transition(monday + 2 * 86400 + 10 * 3600, "empty"),
+            transition(monday + 7 * 86400 + 12 * 3600, "full"),
+            transition(monday + 7 * 86400 + 13 * 3600, "empty"),
+            transition(monday + 9 * 86400 + 12 * 3600, "full"),
+        ];
+        let entries: Vec<&Entry> = entries.iter().collect();
+        let stats = Statistics::compute(&entries, monday, monday + 7 * 86400 - 1, &Utc);
+        assert_eq!(stats.days, 7);
+        assert_eq!(stats.deliveries, 2);
+        assert_eq!(stats.days_without_mail, 5);
+        assert_eq!(stats.weekdays, [1, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(stats.median_arrival().map(format_time), Some("09:51".into()));
+        assert_eq!(stats.average_wait().map(format_wait), Some("4h 30min".into()));
+        assert_eq!(stats.format_weekdays(), "Mon 1, Tue 0, Wed 1, Thu 0, Fri 0, Sat 0, Sun 0");
+
+        // The last delivery was not picked up yet
+        let stats = Statistics::compute(&entries, monday + 7 * 86400, monday + 14 * 86400 - 1, &Utc);
+        assert_eq!(stats.deliveries, 2);
+        assert_eq!(stats.waits, vec![3600]);
+
+        // A daily digest at 20:00
+        let stats = Statistics::compute(&entries, monday + 20 * 3600 - 86400, monday + 20 * 3600, &Utc);
+        assert_eq!(stats.days, 1);
+        assert_eq!(stats.deliveries, 2);
+        assert_eq!(stats.days_without_mail, 0);
+
+        let stats = Statistics::compute(&[], monday, monday + 3600, &Utc);
+        assert_eq!(stats.days_without_mail, 1);
+        assert_eq!(stats.median_arrival(), None);
+        assert_eq!(stats.average_wait(), None);
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(format_wait(3 * 3600), "3h");
+        assert_eq!(format_wait(45 * 60), "45min");
+        assert_eq!(format_wait(90 * 60), "1h 30min");
+    }
+
+    #[test]
+    fn test_schedule() {
+        use chrono::NaiveDate;
+
+        let at = |day: u32, hour: u32, minute: u32| NaiveDate::from_ymd_opt(2018, 1, day).unwrap().and_hms_opt(hour, minute, 0).unwrap();
+        assert_eq!("daily 20:00".parse(), Ok(DigestSchedule::Daily(1200)));
+        assert_eq!("Sun 18:30".parse(), Ok(DigestSchedule::Weekly(6, 1110)));
+        assert!("weekly".parse::<DigestSchedule>().is_err());
+        assert!("someday 18:00".parse::<DigestSchedule>().is_err());
+
+        let daily = DigestSchedule::Daily(1200);
+        assert!(daily.is_due(at(1, 19, 59), at(1, 20, 0)));
+        assert!(!daily.is_due(at(1, 20, 0), at(1, 20, 1)));
+        let weekly: DigestSchedule = "sun 00:00".parse().unwrap();
+        assert!(weekly.is_due(at(6, 23, 59), at(7, 0, 0)));
+        assert!(!weekly.is_due(at(5, 23, 59), at(6, 0, 0)));
+        let midnight: DigestSchedule = "daily 24:00".parse().unwrap();
+        assert!(midnight.is_due(at(1, 23, 59), at(2, 0, 0)));
+    }
+}
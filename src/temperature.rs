@@ -0,0 +1,92 @@
+//! Temperature alerting.
+
+use std::str::FromStr;
+
+
+/// A state is only left again when the temperature is back this many degrees
+/// within the limits, so that a temperature jittering around a limit does not
+/// cause repeated alerts.
+static HYSTERESIS: f32 = 1.0;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum TemperatureState {
+    Normal,
+    TooCold,
+    TooHot,
+}
+
+impl Default for TemperatureState {
+    fn default() -> Self {
+        TemperatureState::Normal
+    }
+}
+
+/// Temperature limits of a device. Both limits are optional.
+#[derive(Debug, Default, PartialEq, Copy, Clone)]
+pub struct TemperatureLimits {
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+}
+
+impl TemperatureLimits {
+    /// Return the new state for the measured temperature.
+    pub fn state(&self, prev: TemperatureState, temperature: f32) -> TemperatureState {
+        let min = self.min.map(|min| if prev == TemperatureState::TooCold { min + HYSTERESIS } else { min });
+        let max = self.max.map(|max| if prev == TemperatureState::TooHot { max - HYSTERESIS } else { max });
+        if min.map_or(false, |min| temperature < min) {
+            TemperatureState::TooCold
+        } else if max.map_or(false, |max| temperature > max) {
+            TemperatureState::TooHot
+        } else {
+            TemperatureState::Normal
+        }
+    }
+}
+
+/// Parse limits in the format `<min>/<max>`, e.g. `2/30`. Either limit may
+/// be empty, e.g. `2/` for a frost warning only.
+impl FromStr for TemperatureLimits {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let parse = |part: Option<&str>| -> Result<Option<f32>, String> {
+            match part.map(|p| p.trim()) {
+                None | Some("") => Ok(None),
+                Some(p) => p.parse().map(Some).map_err(|_| format!("Invalid temperature: {}", p)),
+            }
+        };
+        let mut parts = val.splitn(2, '/');
+        let min = parse(parts.next())?;
+        let max = parse(parts.next())?;
+        match (min, max) {
+            (Some(min), Some(max)) if min >= max => Err(format!("Minimum temperature must be below maximum: {}", val)),
+            _ => Ok(TemperatureLimits { min: min, max: max }),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("2/30".parse(), Ok(TemperatureLimits { min: Some(2.0), max: Some(30.0) }));
+        assert_eq!("-5/".parse(), Ok(TemperatureLimits { min: Some(-5.0), max: None }));
+        assert!("30/2".parse::<TemperatureLimits>().is_err());
+        assert!("cold/hot".parse::<TemperatureLimits>().is_err());
+    }
+
+    #[test]
+    fn test_state() {
+        let limits = TemperatureLimits { min: Some(2.0), max: Some(30.0) };
+        assert_eq!(limits.state(TemperatureState::Normal, 20.0), TemperatureState::Normal);
+        assert_eq!(limits.state(TemperatureState::Normal, 1.5), TemperatureState::TooCold);
+        assert_eq!(limits.state(TemperatureState::TooCold, 2.5), TemperatureState::TooCold);
+        assert_eq!(limits.state(TemperatureState::TooCold, 3.0), TemperatureState::Normal);
+        assert_eq!(limits.state(TemperatureState::Normal, 31.0), TemperatureState::TooHot);
+        assert_eq!(limits.state(TemperatureState::TooHot, 29.5), TemperatureState::TooHot);
+        assert_eq!(TemperatureLimits::default().state(TemperatureState::Normal, -40.0), TemperatureState::Normal);
+    }
+}
@@ -0,0 +1,491 @@
+//! Localised notification templates.
+//!
+//! Templates contain placeholders like `{distance}` that are replaced when a
+//! notification is rendered. The built-in templates can be overridden by
+//! files named `<template>.<language>.txt` (e.g. `full.de.txt`) in a template
+//! directory.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
+pub enum Language {
+    En,
+    De,
+    Fr,
+}
+
+static LANGUAGES: &'static [Language] = &[Language::En, Language::De, Language::Fr];
+
+impl Language {
+    pub fn code(&self) -> &'static str {
+        match *self {
+            Language::En => "en",
+            Language::De => "de",
+            Language::Fr => "fr",
+        }
+    }
+}
+
+impl FromStr for Language {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        LANGUAGES.iter()
+            .find(|lang| lang.code() == val.trim().to_lowercase())
+            .cloned()
+            .ok_or_else(|| format!("Unsupported language: {}", val))
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum DistanceUnit {
+    Millimeters,
+    Centimeters,
+    Inches,
+}
+
+impl DistanceUnit {
+    /// Format a distance in millimeters.
+    pub fn format(&self, mm: u16) -> String {
+        match *self {
+            DistanceUnit::Millimeters => format!("{}mm", mm),
+            DistanceUnit::Centimeters => format!("{:.1}cm", (mm as f32) / 10.0),
+            DistanceUnit::Inches => format!("{:.1}in", (mm as f32) / 25.4),
+        }
+    }
+}
+
+impl FromStr for DistanceUnit {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        match val.trim() {
+            "mm" => Ok(DistanceUnit::Millimeters),
+            "cm" => Ok(DistanceUnit::Centimeters),
+            "in" => Ok(DistanceUnit::Inches),
+            _ => Err(format!("Unsupported distance unit: {}", val)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
+pub enum TemplateKind {
+    /// The mailbox changed from empty to full.
+    Full,
+    /// The mailbox changed from full to empty.
+    Empty,
+    /// Somebody claimed to have picked up the mail.
+    PickedUp,
+    /// Device statistics, available as `{stats}` in other templates.
+    Stats,
+    /// Header of the summary that is sent after quiet hours.
+    Summary,
+    /// An uplink could not be decoded.
+    DecodeError,
+    /// The battery voltage fell below the low threshold.
+    BatteryLow,
+    /// The battery voltage fell below the critical threshold.
+    BatteryCritical,
+    /// The battery voltage recovered, e.g. after a battery swap.
+    BatteryOk,
+    /// The battery will soon reach its cut-off voltage.
+    BatteryForecast,
+    /// Estimated battery life, available as `{battery}` in other templates.
+    BatteryLife,
+    /// A device did not send an uplink for too long.
+    Offline,
+    /// An offline device sent an uplink again.
+    BackOnline,
+    /// The temperature fell below the minimum.
+    TemperatureLow,
+    /// The temperature rose above the maximum.
+    TemperatureHigh,
+    /// A device joined the network.
+    Joined,
+    /// The link quality of a device stayed below the thresholds.
+    CoveragePoor,
+    /// The link quality of a device recovered.
+    CoverageOk,
+    /// A device nears the daily airtime budget.
+    Airtime,
+    /// A device is stuck at SF11 or SF12.
+    AdrStuck,
+    /// The data rate of a device changes back and forth.
+    AdrOscillating,
+    /// Daily delivery statistics of a device.
+    DigestDaily,
+    /// Weekly delivery statistics of a device.
+    DigestWeekly,
+    /// Arrival and waiting times, available as `{details}` in the digest
+    /// templates.
+    DigestDetails,
+}
+
+static TEMPLATE_KINDS: &'static [TemplateKind] = &[
+    TemplateKind::Full,
+    TemplateKind::Empty,
+    TemplateKind::PickedUp,
+    TemplateKind::Stats,
+    TemplateKind::Summary,
+    TemplateKind::DecodeError,
+    TemplateKind::BatteryLow,
+    TemplateKind::BatteryCritical,
+    TemplateKind::BatteryOk,
+    TemplateKind::BatteryForecast,
+    TemplateKind::BatteryLife,
+    TemplateKind::Offline,
+    TemplateKind::BackOnline,
+    TemplateKind::TemperatureLow,
+    TemplateKind::TemperatureHigh,
+    TemplateKind::Joined,
+    TemplateKind::CoveragePoor,
+    TemplateKind::CoverageOk,
+    TemplateKind::Airtime,
+    TemplateKind::AdrStuck,
+    TemplateKind::AdrOscillating,
+    TemplateKind::DigestDaily,
+    TemplateKind::DigestWeekly,
+    TemplateKind::DigestDetails,
+];
+
+impl TemplateKind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            TemplateKind::Full => "full",
+            TemplateKind::Empty => "empty",
+            TemplateKind::PickedUp => "picked_up",
+            TemplateKind::Stats => "stats",
+            TemplateKind::Summary => "summary",
+            TemplateKind::DecodeError => "decode_error",
+            TemplateKind::BatteryLow => "battery_low",
+            TemplateKind::BatteryCritical => "battery_critical",
+            TemplateKind::BatteryOk => "battery_ok",
+            TemplateKind::BatteryForecast => "battery_forecast",
+            TemplateKind::BatteryLife => "battery_life",
+            TemplateKind::Offline => "offline",
+            TemplateKind::BackOnline => "back_online",
+            TemplateKind::TemperatureLow => "temperature_low",
+            TemplateKind::TemperatureHigh => "temperature_high",
+            TemplateKind::Joined => "joined",
+            TemplateKind::CoveragePoor => "coverage_poor",
+            TemplateKind::CoverageOk => "coverage_ok",
+            TemplateKind::Airtime => "airtime",
+            TemplateKind::AdrStuck => "adr_stuck",
+            TemplateKind::AdrOscillating => "adr_oscillating",
+            TemplateKind::DigestDaily => "digest_daily",
+            TemplateKind::DigestWeekly => "digest_weekly",
+            TemplateKind::DigestDetails => "digest_details",
+        }
+    }
+}
+
+fn default_template(kind: TemplateKind, lang: Language) -> &'static str {
+    match (kind, lang) {
+        (TemplateKind::Full, Language::En) =>
+            "\u{1F4EC} Mailbox is full! Distance changed from {prev_distance} to {distance}.{stats}",
+        (TemplateKind::Full, Language::De) =>
+            "\u{1F4EC} Der Briefkasten ist voll! Die Distanz hat sich von {prev_distance} auf {distance} geändert.{stats}",
+        (TemplateKind::Full, Language::Fr) =>
+            "\u{1F4EC} La boîte aux lettres est pleine ! La distance est passée de {prev_distance} à {distance}.{stats}",
+        (TemplateKind::Empty, Language::En) =>
+            "\u{1F4ED} Mailbox was emptied. Distance changed from {prev_distance} to {distance}.{stats} \
+             Reply *took* if you picked up the mail.",
+        (TemplateKind::Empty, Language::De) =>
+            "\u{1F4ED} Der Briefkasten wurde geleert. Die Distanz hat sich von {prev_distance} auf {distance} geändert.{stats} \
+             Antworte *took*, wenn du die Post geholt hast.",
+        (TemplateKind::Empty, Language::Fr) =>
+            "\u{1F4ED} La boîte aux lettres a été vidée. La distance est passée de {prev_distance} à {distance}.{stats} \
+             Répondez *took* si vous avez pris le courrier.",
+        (TemplateKind::PickedUp, Language::En) => "\u{1F4ED} {name} picked up the mail.",
+        (TemplateKind::PickedUp, Language::De) => "\u{1F4ED} {name} hat die Post geholt.",
+        (TemplateKind::PickedUp, Language::Fr) => "\u{1F4ED} {name} a pris le courrier.",
+        (TemplateKind::Stats, Language::En) => " (_Voltage: {voltage}V{battery}, temperature: {temperature}°C._)",
+        (TemplateKind::Stats, Language::De) => " (_Spannung: {voltage}V{battery}, Temperatur: {temperature}°C._)",
+        (TemplateKind::Stats, Language::Fr) => " (_Tension : {voltage}V{battery}, température : {temperature}°C._)",
+        (TemplateKind::BatteryLife, Language::En) => ", ~{battery_days} days left",
+        (TemplateKind::BatteryLife, Language::De) => ", noch ~{battery_days} Tage",
+        (TemplateKind::BatteryLife, Language::Fr) => ", ~{battery_days} jours restants",
+        (TemplateKind::Summary, Language::En) => "\u{1F319} {count} notifications during your quiet hours:",
+        (TemplateKind::Summary, Language::De) => "\u{1F319} {count} Benachrichtigungen während deiner Ruhezeit:",
+        (TemplateKind::Summary, Language::Fr) => "\u{1F319} {count} notifications pendant vos heures de repos :",
+        (TemplateKind::DecodeError, Language::En) => "\u{26A0} Could not decode uplink from {device}: {error}",
+        (TemplateKind::DecodeError, Language::De) => "\u{26A0} Uplink von {device} konnte nicht dekodiert werden: {error}",
+        (TemplateKind::DecodeError, Language::Fr) => "\u{26A0} Impossible de décoder l'uplink de {device} : {error}",
+        (TemplateKind::BatteryLow, Language::En) =>
+            "\u{1F50B} The battery of {device} is low ({voltage}V). Please replace it soon.",
+        (TemplateKind::BatteryLow, Language::De) =>
+            "\u{1F50B} Die Batterie von {device} ist schwach ({voltage}V). Bitte bald ersetzen.",
+        (TemplateKind::BatteryLow, Language::Fr) =>
+            "\u{1F50B} La batterie de {device} est faible ({voltage}V). Veuillez la remplacer bientôt.",
+        (TemplateKind::BatteryCritical, Language::En) =>
+            "\u{26A0} The battery of {device} is almost empty ({voltage}V)! Replace it now.",
+        (TemplateKind::BatteryCritical, Language::De) =>
+            "\u{26A0} Die Batterie von {device} ist fast leer ({voltage}V)! Bitte sofort ersetzen.",
+        (TemplateKind::BatteryCritical, Language::Fr) =>
+            "\u{26A0} La batterie de {device} est presque vide ({voltage}V) ! Remplacez-la maintenant.",
+        (TemplateKind::BatteryOk, Language::En) => "\u{1F50B} The battery of {device} is fine again ({voltage}V).",
+        (TemplateKind::BatteryOk, Language::De) => "\u{1F50B} Die Batterie von {device} ist wieder in Ordnung ({voltage}V).",
+        (TemplateKind::BatteryForecast, Language::En) =>
+            "\u{1F50B} The battery of {device} will probably run out in about {battery_days} days ({voltage}V). \
+             Time to get new batteries.",
+        (TemplateKind::BatteryForecast, Language::De) =>
+            "\u{1F50B} Die Batterie von {device} ist voraussichtlich in etwa {battery_days} Tagen leer ({voltage}V). \
+             Zeit, neue Batterien zu besorgen.",
+        (TemplateKind::BatteryForecast, Language::Fr) =>
+            "\u{1F50B} La batterie de {device} sera probablement vide dans environ {battery_days} jours ({voltage}V). \
+             Il est temps d'acheter de nouvelles piles.",
+        (TemplateKind::BatteryOk, Language::Fr) => "\u{1F50B} La batterie de {device} est de nouveau en bon état ({voltage}V).",
+        (TemplateKind::Offline, Language::En) =>
+            "\u{1F4F4} {device} has not sent anything since {last_seen}. \
+             Check the battery and the gateway, the mailbox may be full!",
+        (TemplateKind::Offline, Language::De) =>
+            "\u{1F4F4} {device} hat seit {last_seen} nichts mehr gesendet. \
+             Bitte Batterie und Gateway prüfen, der Briefkasten könnte voll sein!",
+        (TemplateKind::Offline, Language::Fr) =>
+            "\u{1F4F4} {device} n'a rien envoyé depuis {last_seen}. \
+             Vérifiez la batterie et la passerelle, la boîte aux lettres est peut-être pleine !",
+        (TemplateKind::BackOnline, Language::En) => "\u{2705} {device} is back online (last seen before: {last_seen}).",
+        (TemplateKind::BackOnline, Language::De) => "\u{2705} {device} ist wieder online (zuvor zuletzt gesehen: {last_seen}).",
+        (TemplateKind::BackOnline, Language::Fr) => "\u{2705} {device} est de nouveau en ligne (vu pour la dernière fois : {last_seen}).",
+        (TemplateKind::TemperatureLow, Language::En) =>
+            "\u{2744} It's {temperature}°C at {device}, below the minimum of {limit}°C. Beware of frost!",
+        (TemplateKind::TemperatureLow, Language::De) =>
+            "\u{2744} Bei {device} ist es {temperature}°C, unter dem Minimum von {limit}°C. Achtung Frost!",
+        (TemplateKind::TemperatureLow, Language::Fr) =>
+            "\u{2744} Il fait {temperature}°C à {device}, en dessous du minimum de {limit}°C. Attention au gel !",
+        (TemplateKind::TemperatureHigh, Language::En) =>
+            "\u{1F321} It's {temperature}°C at {device}, above the maximum of {limit}°C.",
+        (TemplateKind::TemperatureHigh, Language::De) =>
+            "\u{1F321} Bei {device} ist es {temperature}°C, über dem Maximum von {limit}°C.",
+        (TemplateKind::TemperatureHigh, Language::Fr) =>
+            "\u{1F321} Il fait {temperature}°C à {device}, au-dessus du maximum de {limit}°C.",
+        (TemplateKind::Joined, Language::En) => "\u{1F4E1} {device} joined the network (DevAddr {dev_addr}, {data_rate}).",
+        (TemplateKind::Joined, Language::De) => "\u{1F4E1} {device} ist dem Netzwerk beigetreten (DevAddr {dev_addr}, {data_rate}).",
+        (TemplateKind::Joined, Language::Fr) => "\u{1F4E1} {device} a rejoint le réseau (DevAddr {dev_addr}, {data_rate}).",
+        (TemplateKind::CoveragePoor, Language::En) =>
+            "\u{1F4F6} The radio coverage of {device} is poor (RSSI {rssi}dBm, SNR {snr}dB, {gateways} gateway(s)). \
+             The device may soon drop off the network.",
+        (TemplateKind::CoveragePoor, Language::De) =>
+            "\u{1F4F6} Der Funkempfang von {device} ist schlecht (RSSI {rssi}dBm, SNR {snr}dB, {gateways} Gateway(s)). \
+             Das Gerät könnte bald nicht mehr erreichbar sein.",
+        (TemplateKind::CoveragePoor, Language::Fr) =>
+            "\u{1F4F6} La couverture radio de {device} est mauvaise (RSSI {rssi}dBm, SNR {snr}dB, {gateways} passerelle(s)). \
+             L'appareil risque de perdre la connexion au réseau.",
+        (TemplateKind::CoverageOk, Language::En) =>
+            "\u{1F4F6} The radio coverage of {device} is good again (RSSI {rssi}dBm, SNR {snr}dB, {gateways} gateway(s)).",
+        (TemplateKind::CoverageOk, Language::De) =>
+            "\u{1F4F6} Der Funkempfang von {device} ist wieder gut (RSSI {rssi}dBm, SNR {snr}dB, {gateways} Gateway(s)).",
+        (TemplateKind::CoverageOk, Language::Fr) =>
+            "\u{1F4F6} La couverture radio de {device} est de nouveau bonne (RSSI {rssi}dBm, SNR {snr}dB, {gateways} passerelle(s)).",
+        (TemplateKind::Airtime, Language::En) =>
+            "\u{23F1} {device} used {airtime}s of its {budget}s daily airtime budget in the last 24 hours. \
+             At {data_rate} with an uplink every {interval} min, it uses {projected}s per day.",
+        (TemplateKind::Airtime, Language::De) =>
+            "\u{23F1} {device} hat in den letzten 24 Stunden {airtime}s von {budget}s Sendezeit pro Tag verbraucht. \
+             Mit {data_rate} und einem Uplink alle {interval} Minuten sind es {projected}s pro Tag.",
+        (TemplateKind::Airtime, Language::Fr) =>
+            "\u{23F1} {device} a utilisé {airtime}s de ses {budget}s de temps d'antenne quotidien ces dernières 24 heures. \
+             Avec {data_rate} et un uplink toutes les {interval} min, cela fait {projected}s par jour.",
+        (TemplateKind::AdrStuck, Language::En) =>
+            "\u{1F4F6} {device} keeps sending at {data_rate}, which drains the battery. \
+             Consider moving the gateway closer.",
+        (TemplateKind::AdrStuck, Language::De) =>
+            "\u{1F4F6} {device} sendet ständig mit {data_rate}, das leert die Batterie. \
+             Vielleicht kann das Gateway näher platziert werden.",
+        (TemplateKind::AdrStuck, Language::Fr) =>
+            "\u{1F4F6} {device} émet constamment en {data_rate}, ce qui vide la batterie. \
+             Essayez de rapprocher la passerelle.",
+        (TemplateKind::AdrOscillating, Language::En) =>
+            "\u{1F4F6} The data rate of {device} changed {changes} times within the last uplinks (ADR oscillation).",
+        (TemplateKind::AdrOscillating, Language::De) =>
+            "\u{1F4F6} Die Datenrate von {device} hat sich in den letzten Uplinks {changes} Mal geändert (ADR-Oszillation).",
+        (TemplateKind::AdrOscillating, Language::Fr) =>
+            "\u{1F4F6} Le débit de {device} a changé {changes} fois lors des derniers uplinks (oscillation ADR).",
+        (TemplateKind::DigestDaily, Language::En) => "\u{1F4CA} {device} in the last 24 hours: {deliveries} deliveries{details}.",
+        (TemplateKind::DigestDaily, Language::De) => "\u{1F4CA} {device} in den letzten 24 Stunden: {deliveries} Zustellungen{details}.",
+        (TemplateKind::DigestDaily, Language::Fr) => "\u{1F4CA} {device} ces dernières 24 heures : {deliveries} livraisons{details}.",
+        (TemplateKind::DigestWeekly, Language::En) =>
+            "\u{1F4CA} {device} this week: {deliveries} deliveries{details}, {days_without_mail} days without mail.",
+        (TemplateKind::DigestWeekly, Language::De) =>
+            "\u{1F4CA} {device} diese Woche: {deliveries} Zustellungen{details}, {days_without_mail} Tage ohne Post.",
+        (TemplateKind::DigestWeekly, Language::Fr) =>
+            "\u{1F4CA} {device} cette semaine : {deliveries} livraisons{details}, {days_without_mail} jours sans courrier.",
+        (TemplateKind::DigestDetails, Language::En) => ", median arrival {arrival}, mail waited {wait} on average",
+        (TemplateKind::DigestDetails, Language::De) => ", Ankunft im Median um {arrival}, die Post lag im Schnitt {wait} im Briefkasten",
+        (TemplateKind::DigestDetails, Language::Fr) => ", arrivée médiane à {arrival}, le courrier a attendu {wait} en moyenne",
+    }
+}
+
+/// Replace all `{name}` placeholders with their values.
+///
+/// Unknown placeholders are left untouched. The template is processed in a
+/// single pass, so placeholders within the values are not replaced.
+pub fn render(template: &str, vars: &[(&str, String)]) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let tail = &rest[start + 1..];
+        let var = tail.find('}').and_then(|end| {
+            vars.iter().find(|&&(name, _)| name == &tail[..end]).map(|&(_, ref value)| (value, end))
+        });
+        match var {
+            Some((value, end)) => {
+                rendered.push_str(value);
+                rest = &tail[end + 1..];
+            },
+            None => {
+                rendered.push('{');
+                rest = tail;
+            },
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+#[derive(Debug, Default)]
+pub struct Templates {
+    custom: HashMap<(TemplateKind, Language), String>,
+}
+
+impl Templates {
+    /// Load custom templates from the specified directory.
+    pub fn load(dir: &Path) -> Result<Templates, String> {
+        let mut templates = Templates::default();
+        for kind in TEMPLATE_KINDS {
+            for lang in LANGUAGES {
+                let path = dir.join(format!("{}.{}.txt", kind.name(), lang.code()));
+                let mut file = match File::open(&path) {
+                    Ok(file) => file,
+                    Err(_) => continue,
+                };
+                let mut contents = String::new();
+                file.read_to_string(&mut contents)
+                    .map_err(|e| format!("Could not read template {}: {}", path.display(), e))?;
+                let trimmed = contents.trim_matches(|c| c == '\n' || c == '\r').to_owned();
+                templates.custom.insert((*kind, *lang), trimmed);
+            }
+        }
+        Ok(templates)
+    }
+
+    pub fn get(&self, kind: TemplateKind, lang: Language) -> &str {
+        match self.custom.get(&(kind, lang)) {
+            Some(template) => template,
+            None => default_template(kind, lang),
+        }
+    }
+
+    /// Render a template.
+    ///
+    /// The `{stats}` placeholder is filled with the stats template if the
+    /// `voltage` and `temperature` variables are available, and removed
+    /// otherwise. The same applies to the `{battery}` placeholder and the
+    /// `battery_days` variable, and to the `{details}` placeholder and the
+    /// `arrival` and `wait` variables.
+    pub fn render(&self, kind: TemplateKind, lang: Language, vars: &[(&str, String)]) -> String {
+        let has_vars = |names: &[&str]| names.iter()
+            .all(|name| vars.iter().any(|&(var, _)| var == *name));
+        let mut vars = vars.to_vec();
+        let battery = if has_vars(&["battery_days"]) {
+            render(self.get(TemplateKind::BatteryLife, lang), &vars)
+        } else {
+            String::new()
+        };
+        vars.push(("battery", battery));
+        let stats = if has_vars(&["voltage", "temperature"]) {
+            render(self.get(TemplateKind::Stats, lang), &vars)
+        } else {
+            String::new()
+        };
+        let details = if has_vars(&["arrival", "wait"]) {
+            render(self.get(TemplateKind::DigestDetails, lang), &vars)
+        } else {
+            String::new()
+        };
+        vars.push(("stats", stats));
+        vars.push(("details", details));
+        render(self.get(kind, lang), &vars)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_templates() {
+        let templates = Templates::default();
+        let vars = vec![
+            ("distance", DistanceUnit::Centimeters.format(212)),
+            ("prev_distance", DistanceUnit::Centimeters.format(352)),
+        ];
+        assert_eq!(
+            templates.render(TemplateKind::Full, Language::En, &vars),
+            "\u{1F4EC} Mailbox is full! Distance changed from 35.2cm to 21.2cm."
+        );
+
+        let mut vars = vars;
+        vars.push(("voltage", "3.78".into()));
+        vars.push(("temperature", "23".into()));
+        assert_eq!(
+            templates.render(TemplateKind::Full, Language::De, &vars),
+            "\u{1F4EC} Der Briefkasten ist voll! Die Distanz hat sich von 35.2cm auf 21.2cm geändert. \
+             (_Spannung: 3.78V, Temperatur: 23°C._)"
+        );
+
+        vars.push(("battery_days", "84".into()));
+        assert_eq!(
+            templates.render(TemplateKind::Full, Language::En, &vars),
+            "\u{1F4EC} Mailbox is full! Distance changed from 35.2cm to 21.2cm. \
+             (_Voltage: 3.78V, ~84 days left, temperature: 23°C._)"
+        );
+    }
+
+    #[test]
+    fn test_digest() {
+        let templates = Templates::default();
+        let mut vars = vec![
+            ("device", "Mailbox".into()),
+            ("deliveries", "5".into()),
+            ("days_without_mail", "2".into()),
+        ];
+        assert_eq!(
+            templates.render(TemplateKind::DigestWeekly, Language::En, &vars),
+            "\u{1F4CA} Mailbox this week: 5 deliveries, 2 days without mail."
+        );
+        vars.push(("arrival", "10:42".into()));
+        vars.push(("wait", "3h".into()));
+        assert_eq!(
+            templates.render(TemplateKind::DigestWeekly, Language::En, &vars),
+            "\u{1F4CA} Mailbox this week: 5 deliveries, median arrival 10:42, mail waited 3h on average, 2 days without mail."
+        );
+        assert_eq!(
+            templates.render(TemplateKind::DigestDaily, Language::En, &vars),
+            "\u{1F4CA} Mailbox in the last 24 hours: 5 deliveries, median arrival 10:42, mail waited 3h on average."
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(render("{device} at {time} {unknown}", &[("device", "Mailbox".into()), ("time", "10:42".into())]),
+                   "Mailbox at 10:42 {unknown}");
+        assert_eq!(render("{device}: {voltage}V {", &[("device", "{voltage}".into()), ("voltage", "3.7".into())]),
+                   "{voltage}: 3.7V {");
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("DE".parse(), Ok(Language::De));
+        assert!("it".parse::<Language>().is_err());
+        assert_eq!(DistanceUnit::Inches.format(254), "10.0in");
+        assert_eq!(DistanceUnit::Millimeters.format(254), "254mm");
+    }
+}
@@ -0,0 +1,392 @@
+//! Parsing of the messages that The Things Network publishes via MQTT.
+
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use data_encoding::BASE64;
+use regex::Regex;
+use serde_json::Value;
+
+
+lazy_static! {
+    static ref DATA_RATE_RE: Regex = Regex::new(r"^SF(\d+)BW(\d+)$").unwrap();
+}
+
+/// The kind of a message, determined by its topic.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Topic {
+    /// `<app-id>/devices/<dev-id>/up`
+    Uplink,
+    /// `<app-id>/devices/<dev-id>/activations` or
+    /// `<app-id>/devices/<dev-id>/events/activations`
+    Activation,
+    Other,
+}
+
+impl Topic {
+    pub fn parse(topic: &str) -> Topic {
+        if topic.ends_with("/up") {
+            Topic::Uplink
+        } else if topic.ends_with("/activations") {
+            Topic::Activation
+        } else {
+            Topic::Other
+        }
+    }
+}
+
+/// Return the device ID that is part of the topic.
+pub fn dev_id(topic: &str) -> &str {
+    topic.split('/').nth(2).unwrap_or("unknown")
+}
+
+/// Return the value at the specified path, e.g. `["metadata", "airtime"]`.
+fn field<'a>(val: &'a Value, path: &[&str]) -> Result<&'a Value, String> {
+    let mut current = val;
+    for key in path {
+        current = current.get(key)
+            .ok_or_else(|| format!("Message does not contain \"{}\" field", path.join(".")))?;
+    }
+    Ok(current)
+}
+
+fn u64_field(val: &Value, path: &[&str]) -> Result<u64, String> {
+    field(val, path)?.as_u64()
+        .ok_or_else(|| format!("The \"{}\" field does not contain a number", path.join(".")))
+}
+
+fn str_field<'a>(val: &'a Value, path: &[&str]) -> Result<&'a str, String> {
+    field(val, path)?.as_str()
+        .ok_or_else(|| format!("The \"{}\" field does not contain a string", path.join(".")))
+}
+
+/// The data rate (modulation) of an uplink.
+#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Clone)]
+pub enum DataRate {
+    /// LoRa with spreading factor and bandwidth (in kHz).
+    Lora { sf: u8, bw: u16 },
+    /// FSK with bit rate (in bit/s).
+    Fsk { bit_rate: u32 },
+    /// LR-FHSS with operating channel width (in kHz).
+    LrFhss { ocw: u16 },
+}
+
+/// Paths of the data rate in TTN v2, TTN v3, ChirpStack v4 and ChirpStack v3
+/// uplinks.
+static DATA_RATE_PATHS: &'static [&'static [&'static str]] = &[
+    &["metadata", "data_rate"],
+    &["uplink_message", "settings", "data_rate"],
+    &["txInfo", "modulation"],
+    &["txInfo"],
+];
+
+/// Convert a bandwidth to kHz. TTN v3 and ChirpStack v4 use Hz, ChirpStack v3
+/// uses kHz.
+fn khz(bw: u64) -> u16 {
+    if bw >= 1000 { (bw / 1000) as u16 } else { bw as u16 }
+}
+
+/// Return the first field of `val` with one of the specified names as
+/// number.
+fn u64_alias(val: &Value, names: &[&str]) -> Option<u64> {
+    names.iter().filter_map(|name| val.get(name)).filter_map(|v| v.as_u64()).next()
+}
+
+fn obj_alias<'a>(val: &'a Value, names: &[&str]) -> Option<&'a Value> {
+    names.iter().filter_map(|name| val.get(name)).find(|v| v.is_object())
+}
+
+impl DataRate {
+    /// Parse the data rate of an uplink or activation.
+    pub fn find(val: &Value) -> Option<DataRate> {
+        DATA_RATE_PATHS.iter()
+            .filter_map(|path| field(val, path).ok())
+            .filter_map(DataRate::parse)
+            .next()
+    }
+
+    /// Parse a data rate, either in the TTN v2 string representation (e.g.
+    /// `SF7BW125`) or as a TTN v3 / ChirpStack object with a `lora`, `fsk`
+    /// or `lr_fhss` member.
+    pub fn parse(val: &Value) -> Option<DataRate> {
+        if let Some(s) = val.as_str() {
+            return s.parse().ok();
+        }
+        if let Some(lora) = obj_alias(val, &["lora", "loRaModulationInfo"]) {
+            let sf = u64_alias(lora, &["spreading_factor", "spreadingFactor"])?;
+            let bw = u64_alias(lora, &["bandwidth"]).unwrap_or(125);
+            return Some(DataRate::Lora { sf: sf as u8, bw: khz(bw) });
+        }
+        if let Some(fsk) = obj_alias(val, &["fsk", "fskModulationInfo"]) {
+            let bit_rate = u64_alias(fsk, &["bit_rate", "bitrate", "datarate"])?;
+            return Some(DataRate::Fsk { bit_rate: bit_rate as u32 });
+        }
+        if let Some(lr_fhss) = obj_alias(val, &["lr_fhss", "lrFhss", "lrFhssModulationInfo"]) {
+            let ocw = u64_alias(lr_fhss, &["operating_channel_width", "operatingChannelWidth"]).unwrap_or(0);
+            return Some(DataRate::LrFhss { ocw: khz(ocw) });
+        }
+        None
+    }
+
+    pub fn sf(&self) -> Option<u8> {
+        match *self {
+            DataRate::Lora { sf, .. } => Some(sf),
+            _ => None,
+        }
+    }
+
+    pub fn bw(&self) -> Option<u16> {
+        match *self {
+            DataRate::Lora { bw, .. } => Some(bw),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DataRate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DataRate::Lora { sf, bw } => write!(f, "SF{}BW{}", sf, bw),
+            DataRate::Fsk { bit_rate } => write!(f, "FSK{}", bit_rate),
+            DataRate::LrFhss { ocw } => write!(f, "LR-FHSS{}", ocw),
+        }
+    }
+}
+
+/// Parse a TTN v2 data rate like `SF7BW125`, or an FSK bit rate like `50000`.
+impl FromStr for DataRate {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        if let Some(captures) = DATA_RATE_RE.captures(val) {
+            let sf = captures.get(1).and_then(|mtch| mtch.as_str().parse().ok());
+            let bw = captures.get(2).and_then(|mtch| mtch.as_str().parse().ok());
+            if let (Some(sf), Some(bw)) = (sf, bw) {
+                return Ok(DataRate::Lora { sf: sf, bw: bw });
+            }
+        }
+        val.parse()
+            .map(|bit_rate| DataRate::Fsk { bit_rate: bit_rate })
+            .map_err(|_| format!("Invalid data rate: {}", val))
+    }
+}
+
+fn max<I: Iterator<Item=f32>>(values: I) -> Option<f32> {
+    values.fold(None, |max, val| match max {
+        Some(max) if max >= val => Some(max),
+        _ => Some(val),
+    })
+}
+
+/// Reception metadata of a gateway that received an uplink.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Gateway {
+    pub id: String,
+    pub rssi: f32,
+    pub snr: f32,
+    pub channel: Option<u64>,
+}
+
+impl Gateway {
+    fn parse(val: &Value) -> Result<Gateway, String> {
+        Ok(Gateway {
+            id: str_field(val, &["gtw_id"])?.to_owned(),
+            rssi: field(val, &["rssi"])?.as_f64().ok_or("The \"rssi\" field does not contain a number")? as f32,
+            snr: field(val, &["snr"])?.as_f64().ok_or("The \"snr\" field does not contain a number")? as f32,
+            channel: u64_field(val, &["channel"]).ok(),
+        })
+    }
+}
+
+/// Paths of the time at which the uplink was received in TTN v2 and TTN v3
+/// uplinks.
+static TIME_PATHS: &'static [&'static [&'static str]] = &[
+    &["metadata", "time"],
+    &["received_at"],
+];
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Uplink {
+    pub deveui: String,
+    pub port: u64,
+    pub counter: u64,
+    pub payload: Vec<u8>,
+    pub airtime: Option<u64>,
+    pub data_rate: Option<DataRate>,
+    pub gateways: Vec<Gateway>,
+    /// The time at which the network server received the uplink.
+    pub time: Option<DateTime<Utc>>,
+}
+
+impl Uplink {
+    pub fn parse(val: &Value) -> Result<Uplink, String> {
+        let payload_raw = str_field(val, &["payload_raw"])?;
+        let payload = BASE64.decode(payload_raw.as_bytes())
+            .map_err(|e| format!("Raw payload is not valid Base64: {}", e))?;
+        Ok(Uplink {
+            deveui: str_field(val, &["hardware_serial"])?.to_owned(),
+            port: u64_field(val, &["port"])?,
+            counter: u64_field(val, &["counter"])?,
+            payload: payload,
+            airtime: u64_field(val, &["metadata", "airtime"]).ok(),
+            data_rate: DataRate::find(val),
+            gateways: match field(val, &["metadata", "gateways"]).ok().and_then(|g| g.as_array()) {
+                Some(gateways) => gateways.iter().filter_map(|g| match Gateway::parse(g) {
+                    Ok(gateway) => Some(gateway),
+                    Err(e) => {
+                        warn!("Ignoring invalid gateway metadata: {}", e);
+                        None
+                    },
+                }).collect(),
+                None => vec![],
+            },
+            time: TIME_PATHS.iter()
+                .filter_map(|path| str_field(val, path).ok())
+                .next()
+                .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+                .map(|time| time.with_timezone(&Utc)),
+        })
+    }
+
+    /// Return the best RSSI of all gateways.
+    pub fn best_rssi(&self) -> Option<f32> {
+        max(self.gateways.iter().map(|g| g.rssi))
+    }
+
+    /// Return the best SNR of all gateways.
+    pub fn best_snr(&self) -> Option<f32> {
+        max(self.gateways.iter().map(|g| g.snr))
+    }
+
+    /// Return the time as nanoseconds since the epoch, or `None` if it is
+    /// unknown, before 1970 or too far in the future.
+    pub fn time_nanos(&self) -> Option<u64> {
+        let time = self.time?;
+        if time.timestamp() < 0 {
+            return None;
+        }
+        (time.timestamp() as u64).checked_mul(1_000_000_000)?
+            .checked_add(time.timestamp_subsec_nanos() as u64)
+    }
+}
+
+/// An OTAA activation (join).
+#[derive(Debug, PartialEq, Clone)]
+pub struct Activation {
+    pub deveui: String,
+    pub dev_addr: String,
+    pub data_rate: Option<DataRate>,
+}
+
+impl Activation {
+    pub fn parse(val: &Value) -> Result<Activation, String> {
+        Ok(Activation {
+            deveui: str_field(val, &["dev_eui"])?.to_owned(),
+            dev_addr: str_field(val, &["dev_addr"])?.to_owned(),
+            data_rate: DataRate::find(val),
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json;
+
+    #[test]
+    fn test_topic() {
+        assert_eq!(Topic::parse("smartmail/devices/mailbox/up"), Topic::Uplink);
+        assert_eq!(Topic::parse("smartmail/devices/mailbox/activations"), Topic::Activation);
+        assert_eq!(Topic::parse("smartmail/devices/mailbox/events/activations"), Topic::Activation);
+        assert_eq!(Topic::parse("smartmail/devices/mailbox/down"), Topic::Other);
+        assert_eq!(dev_id("smartmail/devices/mailbox/up"), "mailbox");
+    }
+
+    #[test]
+    fn test_parse_uplink() {
+        let val = serde_json::from_str(r#"{
+            "hardware_serial": "0004A30B001A2B3C", "port": 102, "counter": 42, "payload_raw": "AYIBLA==",
+            "metadata": {"airtime": 46336000, "data_rate": "SF7BW125", "time": "2018-01-10T20:08:11.123456789Z"}
+        }"#).unwrap();
+        let uplink = Uplink::parse(&val).unwrap();
+        assert_eq!(uplink.deveui, "0004A30B001A2B3C");
+        assert_eq!(uplink.port, 102);
+        assert_eq!(uplink.counter, 42);
+        assert_eq!(uplink.payload, vec![0x01, 0x82, 0x01, 0x2c]);
+        assert_eq!(uplink.airtime, Some(46336000));
+        assert_eq!(uplink.data_rate, Some(DataRate::Lora { sf: 7, bw: 125 }));
+        assert_eq!(uplink.gateways, vec![]);
+        assert_eq!(uplink.best_rssi(), None);
+        assert_eq!(uplink.time_nanos(), Some(1515614891123456789));
+
+        // Times that don't fit into the nanosecond timestamp are ignored
+        for time in &["9999-01-01T00:00:00Z", "1969-12-31T23:59:59Z"] {
+            let val = json!({"hardware_serial": "0004A30B001A2B3C", "port": 102, "counter": 42, "payload_raw": "",
+                             "metadata": {"time": time}});
+            assert!(Uplink::parse(&val).unwrap().time.is_some());
+            assert_eq!(Uplink::parse(&val).unwrap().time_nanos(), None);
+        }
+
+        let val = serde_json::from_str(r#"{"hardware_serial": "0004A30B001A2B3C", "payload_raw": ""}"#).unwrap();
+        assert_eq!(Uplink::parse(&val), Err("Message does not contain \"port\" field".into()));
+    }
+
+    #[test]
+    fn test_parse_gateways() {
+        let val = serde_json::from_str(r#"{
+            "hardware_serial": "0004A30B001A2B3C", "port": 101, "counter": 1, "payload_raw": "",
+            "metadata": {"gateways": [
+                {"gtw_id": "eui-b827ebfffe000001", "channel": 0, "rssi": -112, "snr": -3.5},
+                {"gtw_id": "eui-b827ebfffe000002", "channel": 5, "rssi": -98, "snr": -7.25},
+                {"gtw_id": "eui-b827ebfffe000003"}
+            ]}
+        }"#).unwrap();
+        let uplink = Uplink::parse(&val).unwrap();
+        assert_eq!(uplink.gateways.len(), 2);
+        assert_eq!(uplink.gateways[1].channel, Some(5));
+        assert_eq!(uplink.best_rssi(), Some(-98.0));
+        assert_eq!(uplink.best_snr(), Some(-3.5));
+    }
+
+    #[test]
+    fn test_parse_data_rate() {
+        let parse = |json: &str| DataRate::find(&serde_json::from_str(json).unwrap());
+        assert_eq!(parse(r#"{"metadata": {"data_rate": "SF12BW125"}}"#), Some(DataRate::Lora { sf: 12, bw: 125 }));
+        assert_eq!(parse(r#"{"metadata": {"data_rate": "50000"}}"#), Some(DataRate::Fsk { bit_rate: 50000 }));
+        assert_eq!(
+            parse(r#"{"uplink_message": {"settings": {"data_rate": {"lora": {"bandwidth": 250000, "spreading_factor": 7}}}}}"#),
+            Some(DataRate::Lora { sf: 7, bw: 250 })
+        );
+        assert_eq!(
+            parse(r#"{"uplink_message": {"settings": {"data_rate": {"lr_fhss": {"modulation_type": 0, "operating_channel_width": 137000}}}}}"#),
+            Some(DataRate::LrFhss { ocw: 137 })
+        );
+        assert_eq!(
+            parse(r#"{"txInfo": {"modulation": {"lora": {"bandwidth": 125000, "spreadingFactor": 9}}}}"#),
+            Some(DataRate::Lora { sf: 9, bw: 125 })
+        );
+        assert_eq!(
+            parse(r#"{"txInfo": {"loRaModulationInfo": {"bandwidth": 125, "spreadingFactor": 10}}}"#),
+            Some(DataRate::Lora { sf: 10, bw: 125 })
+        );
+        assert_eq!(parse(r#"{"txInfo": {"fskModulationInfo": {"datarate": 50000}}}"#), Some(DataRate::Fsk { bit_rate: 50000 }));
+        assert_eq!(parse(r#"{"metadata": {"data_rate": "foo"}}"#), None);
+        assert_eq!(DataRate::Lora { sf: 7, bw: 125 }.to_string(), "SF7BW125");
+    }
+
+    #[test]
+    fn test_parse_activation() {
+        let val = serde_json::from_str(r#"{
+            "app_eui": "70B3D57EF0001234", "dev_eui": "0004A30B001A2B3C", "dev_addr": "26011234",
+            "metadata": {"time": "2018-01-10T20:08:11.123Z", "data_rate": "SF12BW125"}
+        }"#).unwrap();
+        assert_eq!(Activation::parse(&val), Ok(Activation {
+            deveui: "0004A30B001A2B3C".into(),
+            dev_addr: "26011234".into(),
+            data_rate: Some(DataRate::Lora { sf: 12, bw: 125 }),
+        }));
+    }
+}